@@ -144,10 +144,37 @@ impl AcpiController {
         cpu
     }
 
+    /// Returns the APIC ids of the application processors that can be started.
+    pub fn get_ap_apic_ids(&self) -> Vec<u32> {
+        let platform_info = self.acpi.platform_info().expect("Failed to get platform info!");
+        match platform_info.processor_info {
+            Some(info) => info
+                .application_processors
+                .iter()
+                .filter(|processor| processor.state == acpi::platform::ProcessorState::WaitingForSipi)
+                .map(|processor| processor.local_apic_id as u32)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
     pub fn get_hpet_info(&self) -> acpi::HpetInfo {
         acpi::HpetInfo::new(&self.acpi).expect("ACPI table has no information on HPET!")
     }
 
+    /// Returns the CMOS register holding the century, 0 if the machine doesn't have one.
+    pub fn get_century_register(&self) -> u8 {
+        const FADT_CENTURY_OFFSET: usize = 108;
+
+        match self.acpi.sdts.get(&acpi::sdt::Signature::FADT) {
+            Some(fadt) if fadt.length as usize > FADT_CENTURY_OFFSET => {
+                let address = self.phys_mem_offset + (fadt.physical_address + FADT_CENTURY_OFFSET) as u64;
+                unsafe { core::ptr::read_volatile(address as *const u8) }
+            },
+            _ => 0,
+        }
+    }
+
     pub fn get_apic_addr(&self) -> u64 {
         let platform_info = self.acpi.platform_info().expect("Failed to get platform info!");
         let interrupt_model = platform_info.interrupt_model;
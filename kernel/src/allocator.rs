@@ -29,15 +29,17 @@ pub fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    let mut flush_batch = crate::memory::tlb::FlushBatch::new();
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+            flush_batch.add(page, mapper.map_to(page, frame, flags, frame_allocator)?);
         };
     }
+    flush_batch.flush_local();
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -1,20 +1,23 @@
 use elfloader::*;
 
-use crate::memory::memory_write;
+use crate::memory::AddressSpace;
 
-pub struct CustomElfLoader {
+/// Loads an ELF file into an address space that doesn't have to be active.
+pub struct CustomElfLoader<'a> {
     vbase: u64, //Base offset for all loaded ELF files using this loader
+    address_space: &'a mut AddressSpace,
 }
 
-impl CustomElfLoader {
-    pub fn new(vbase: u64) -> Self {
+impl<'a> CustomElfLoader<'a> {
+    pub fn new(vbase: u64, address_space: &'a mut AddressSpace) -> Self {
         Self {
-            vbase
+            vbase,
+            address_space,
         }
     }
 }
 
-impl ElfLoader for CustomElfLoader {
+impl<'a> ElfLoader for CustomElfLoader<'a> {
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), &'static str> {
         for header in load_headers {
             let addr = self.vbase + header.virtual_addr();
@@ -24,20 +27,19 @@ impl ElfLoader for CustomElfLoader {
                 header.mem_size(),
                 header.flags()
             );
-            let mut mapper = crate::memory::MAPPER.lock();
-            let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
-            let mapped_area = crate::memory::alloc_user_memory(
-                addr, //No need to align it, done in function
-                header.mem_size() / 4096 + 1, //Get size in pages
-                mapper.as_mut().unwrap(),
-                frame_allocator.as_mut().unwrap(),
-            ).expect("Failed to allocate pages for user memory!");
+            let page_offset = addr % 4096;
+            let size_in_pages = (page_offset + header.mem_size() + 4095) / 4096;
+            {
+                let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+                self.address_space.map_user_memory(
+                    addr, //No need to align it, done in function
+                    size_in_pages,
+                    frame_allocator.as_mut().unwrap(),
+                ).map_err(|_| "Failed to allocate pages for user memory!")?;
+            }
 
             //Zero the data
-            let size = header.mem_size() - header.mem_size() % 4096 + 4096; //Align up
-            for i in 0..size {
-                unsafe { memory_write(addr + i, 0u8); }
-            }
+            self.address_space.zero(addr, header.mem_size() as usize)?;
         }
 
         Ok(())
@@ -57,9 +59,8 @@ impl ElfLoader for CustomElfLoader {
                     self.vbase + entry.get_addend()
                 );
 
-                unsafe { memory_write(addr, self.vbase + entry.get_addend()); }
-
-                Ok(())
+                let value = self.vbase + entry.get_addend();
+                self.address_space.write_bytes(addr, &value.to_le_bytes())
             }
             _ => Err("Unexpected relocation encountered"),
         }
@@ -71,15 +72,7 @@ impl ElfLoader for CustomElfLoader {
         info!("load region into = {:#X} -- {:#X}", start, end);
 
         //Load region into new memory location
-        unsafe {
-            let mut offset = 0;
-            for byte in region {
-                memory_write(start + offset, byte);
-                offset += 1;
-            }
-        }
-
-        Ok(())
+        self.address_space.write_bytes(start, region)
     }
 
     fn tls(
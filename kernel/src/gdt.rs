@@ -4,6 +4,12 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// NMIs and machine checks can hit anywhere, even right after `syscall` on the user stack.
+/// Their stacks are bigger since they format a crash report on them.
+const CRASH_STACK_SIZE: usize = 4096 * 4;
 
 //Found in `src/Cargo.toml`
 pub const KERNEL_STACK_START: u64 = 0xFFFFFF8000000000;
@@ -29,28 +35,46 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            static mut STACK: [u8; CRASH_STACK_SIZE] = [0; CRASH_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + CRASH_STACK_SIZE;
+            stack_end
+        };
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = {
+            static mut STACK: [u8; CRASH_STACK_SIZE] = [0; CRASH_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + CRASH_STACK_SIZE;
+            stack_end
+        };
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {
-            kernel_code_selector: kernel_code_selector,
-            kernel_data_selector: kernel_data_selector,
-            tss_selector: tss_selector,
-            user_code_selector: user_code_selector,
-            user_data_selector: user_data_selector,
-        })
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&TSS);
+}
+
+/// Every CPU gets a GDT with the same layout, only the TSS differs.
+/// `setup_usermode_gdt` relies on the selectors being the same everywhere.
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors {
+        kernel_code_selector: kernel_code_selector,
+        kernel_data_selector: kernel_data_selector,
+        tss_selector: tss_selector,
+        user_code_selector: user_code_selector,
+        user_data_selector: user_data_selector,
+    })
 }
 
 static mut SELECTORS: Selectors = Selectors::new();
@@ -76,27 +100,56 @@ impl Selectors {
     }
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{set_cs, load_ss};
-    use x86_64::instructions::tables::load_tss;
-
+/// Loads the GDT and TSS of the BSP. Returns the TSS so it can be put in the per-CPU data.
+pub fn init() -> *mut TaskStateSegment {
     // trace!("RPL: {:?}", GDT.1.user_code_selector.rpl()); //Prints "3", which is correct
 
     unsafe {
         SELECTORS = GDT.1;
     }
 
-    GDT.0.load();
+    load(&GDT.0, &GDT.1);
+
+    trace!("GDT loaded!");
+    &*TSS as *const TaskStateSegment as *mut TaskStateSegment
+}
+
+/// Creates and loads a GDT and TSS for an application processor, returns the TSS.
+pub fn init_ap() -> *mut TaskStateSegment {
+    use alloc::boxed::Box;
+
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    tss.privilege_stack_table[0] = leak_stack(4096);
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = leak_stack(4096);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = leak_stack(CRASH_STACK_SIZE);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = leak_stack(CRASH_STACK_SIZE);
+    let tss: &'static TaskStateSegment = tss;
+
+    let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(build_gdt(tss)));
+    load(&gdt.0, &gdt.1);
+    tss as *const TaskStateSegment as *mut TaskStateSegment
+}
+
+/// Allocates a stack on the heap that is never freed, returns its end.
+fn leak_stack(size: usize) -> VirtAddr {
+    use alloc::vec;
+    let stack = alloc::boxed::Box::leak(vec![0u8; size].into_boxed_slice());
+    (VirtAddr::from_ptr(stack.as_ptr()) + size).align_down(16u64)
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{set_cs, load_ss};
+    use x86_64::instructions::tables::load_tss;
+
+    gdt.load();
 
     unsafe {
-        set_cs(GDT.1.kernel_code_selector);
-        load_ss(GDT.1.kernel_data_selector);
+        set_cs(selectors.kernel_code_selector);
+        load_ss(selectors.kernel_data_selector);
         trace!("Kernel segments loaded!");
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
         trace!("TSS loaded!");
     }
-
-    trace!("GDT loaded!");
 }
 
 pub fn setup_usermode_gdt() {
@@ -111,6 +164,10 @@ pub fn setup_usermode_gdt() {
     }
 }
 
-pub extern "C" fn syscall_entry() {
-    info!("Syscall Entry!");
+/// Sets the stack the current CPU switches to when an interrupt arrives while running in ring 3.
+/// Unsafe because the caller must make sure no interrupt can observe a half written entry.
+pub unsafe fn set_privilege_stack(stack_end: VirtAddr) {
+    let tss = crate::smp::percpu::current().tss();
+    assert!(!tss.is_null(), "this CPU has no TSS loaded");
+    (*tss).privilege_stack_table[0] = stack_end;
 }
@@ -1,9 +1,19 @@
 use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::PhysAddr;
 
 use crate::interrupts::InterruptIndex;
-use crate::memory::{memory_read_64, memory_write_64, memory_read_32, memory_write_32};
+use crate::memory::mmio::{CacheMode, Mmio};
+
+/// Size of the register block, enough for all 32 channels.
+const HPET_REGISTERS_SIZE: u64 = 0x400;
+
+static HPET_REGISTERS: spin::Once<Mmio> = spin::Once::new();
 
-pub static HPET_BASE_ADDR: AtomicU64 = AtomicU64::new(0xFED0_0000);
+/// Copy of `HPET_Information::period`, readable from interrupt handlers without locking.
+/// Zero until `initialize_hpet` has run.
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
 
 const HPET_REG_GEN_CAP_ID: u64 = 0x000;//-0x007
 const HPET_REG_GEN_CONFIG: u64 = 0x010;//-0x017
@@ -42,79 +52,154 @@ fn hpet_read_irq(channel: u8) -> u32 {
     }
 }
 
+fn hpet_registers() -> &'static Mmio {
+    HPET_REGISTERS.r#try().expect("HPET registers aren't mapped!")
+}
+
 fn hpet_write_32(addr: u64, val: u32) {
-    unsafe {
-        memory_write_32(HPET_BASE_ADDR.load(Ordering::Relaxed) + addr, val)
-    }
+    hpet_registers().write_u32(addr, val)
 }
 
 fn hpet_read_32(addr: u64) -> u32 {
-    unsafe {
-        memory_read_32(HPET_BASE_ADDR.load(Ordering::Relaxed) + addr)
-    }
+    hpet_registers().read_u32(addr)
 }
 
 fn hpet_write_64(addr: u64, val: u64) {
-    unsafe {
-        memory_write_64(HPET_BASE_ADDR.load(Ordering::Relaxed) + addr, val)
-    }
+    hpet_registers().write_u64(addr, val)
 }
 
 fn hpet_read_64(addr: u64) -> u64 {
-    unsafe {
-        memory_read_64(HPET_BASE_ADDR.load(Ordering::Relaxed) + addr)
-    }
+    hpet_registers().read_u64(addr)
 }
 
-/// This function guarantees a timer that will trigger in `timer` amount or longer.
-fn hpet_set_oneshot_timer(channel: u8, mut timer: u64) {
-    let period = HPET_INFO.lock().period;
+/// Returns true once `initialize_hpet` has run.
+pub fn is_initialized() -> bool {
+    HPET_PERIOD_FS.load(Ordering::Relaxed) != 0
+}
 
+/// Returns the frequency of the main counter in Hz.
+pub fn frequency() -> u64 {
+    let period = HPET_PERIOD_FS.load(Ordering::Relaxed);
+    assert!(period != 0, "HPET is not initialized!");
+    1_000_000_000_000_000 / period
 }
 
-/// This function guarantees a timer that will trigger every `timer` amount or longer.
-fn hpet_set_period_timer(channel: u8, mut timer: u64, idt_index: InterruptIndex) {
-    // let period = HPET_INFO.lock().period as u64;
-    // if timer < period {
-    //     timer = period;
-    // }
-    let channel_offset = 0x20 * channel as u64;
-    trace!("TIMER: {}", timer);
-    if (hpet_read_64(HPET_REG_TMR_CONCAP + channel_offset) & (1<<4)) == 0 {
-        panic!("Cannot enable periodic mode on a timer that does not support periodic mode!");
+/// Returns the current value of the main counter, which counts up at `HPET_Information::freq`.
+pub fn read_main_counter() -> u64 {
+    hpet_read_64(HPET_REG_MAIN_CNT_V)
+}
+
+/// Spins until `duration` has passed. Only for when sleeping isn't possible, like early boot.
+pub fn busy_wait(duration: Duration) {
+    let end = read_main_counter() + duration_to_ticks(duration);
+    while read_main_counter() < end {
+        core::sync::atomic::spin_loop_hint();
     }
+}
+
+/// Converts a duration to main counter ticks, rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let period = HPET_PERIOD_FS.load(Ordering::Relaxed) as u128;
+    assert!(period != 0, "HPET is not initialized!");
+    let femtos = duration.as_nanos() * 1_000_000;
+    ((femtos + period - 1) / period) as u64
+}
+
+/// Converts main counter ticks to a duration.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let period = HPET_PERIOD_FS.load(Ordering::Relaxed) as u128;
+    assert!(period != 0, "HPET is not initialized!");
+    Duration::from_nanos((ticks as u128 * period / 1_000_000) as u64)
+}
+
+/// IOAPIC IRQs already taken by an HPET channel, so channels don't steal each other's pin.
+static HPET_USED_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Picks an IOAPIC IRQ for `channel` and routes it to `idt_index`. Returns the value for the
+/// routing field of the channel's configuration register.
+///
+/// If every IRQ the channel can use is taken already, the pin is shared and interrupts arrive
+/// on the vector of whichever channel routed it first.
+fn hpet_route_irq(channel: u8, idt_index: InterruptIndex) -> u32 {
     let ioapic_irq_allowed = hpet_read_irq(channel);
     trace!("HPET IRQ: 0b{:032b}", ioapic_irq_allowed);
+    let used = HPET_USED_IRQS.load(Ordering::Relaxed) as u32;
     let mut ioapic_irq: u32 = 0;
+    let mut shared = true;
     'search: for i in 0..32 {
         if ioapic_irq_allowed & (0x1 << i) != 0 {
             trace!("[HPET] Available IRQ: {}", i);
-            ioapic_irq = i;
-            break 'search;
+            if shared {
+                ioapic_irq = i; //First allowed one, in case we have to share
+            }
+            if used & (0x1 << i) == 0 {
+                ioapic_irq = i;
+                shared = false;
+                break 'search;
+            }
         }
     }
+    HPET_USED_IRQS.fetch_or(1 << ioapic_irq, Ordering::Relaxed);
 
     //Only needed for QEMU
     ioapic_irq += 9;
 
-    //TODO: 64 bit timer stuff probably only works when the HPET supports 64 bit mode lol
-    hpet_write_64(HPET_REG_TMR_CONCAP + channel_offset, ((ioapic_irq as u64) << 9) | (1<<2) | (1<<3) | (1<<6));
-    hpet_write_64(HPET_REG_TMR_COMP_V + channel_offset, hpet_read_64(HPET_REG_MAIN_CNT_V) + timer);
-    hpet_write_64(HPET_REG_TMR_COMP_V + channel_offset, timer);
+    if shared {
+        warn!("[HPET] Channel {} shares IRQ {} with another channel", channel, ioapic_irq);
+    } else {
+        use crate::interrupts::ioapic::{self, LineMode};
+        //The comparators are left in edge triggered mode
+        unsafe {
+            ioapic::route(ioapic_irq, crate::smp::current_apic_id(), idt_index.as_u8(), LineMode::ISA)
+                .unwrap_or_else(|err| panic!("Failed to route the HPET IRQ: {}", err));
+        }
+    }
+    ioapic_irq
+}
+
+/// Sets up `channel` as a one-shot timer raising `idt_index`. Nothing fires until
+/// `hpet_set_oneshot_timer` arms it.
+pub fn hpet_setup_oneshot_timer(channel: u8, idt_index: InterruptIndex) {
+    let channel_offset = 0x20 * channel as u64;
+    if (hpet_read_64(HPET_REG_TMR_CONCAP + channel_offset) & (1<<5)) == 0 {
+        //32 bit comparators would wrap way before the deadlines we use
+        panic!("Cannot use HPET channel {} as a one-shot timer, it only has a 32 bit comparator!", channel);
+    }
 
-    use crate::interrupts::ioapic;
-    unsafe { ioapic::ioapic_set_irq(ioapic_irq, 0, idt_index.as_u8()); }
+    // Park the comparator as far away as possible before enabling the interrupt
+    hpet_write_64(HPET_REG_TMR_COMP_V + channel_offset, u64::MAX);
+    let ioapic_irq = hpet_route_irq(channel, idt_index);
+    //Edge triggered, not periodic
+    hpet_write_64(HPET_REG_TMR_CONCAP + channel_offset, ((ioapic_irq as u64) << 9) | (1<<2));
 }
 
-/// Collects a bunch of information of HPET and enables a periodic timer on the first
-/// channel, meant for use in the task scheduler.
-pub fn initialize_hpet() {
+/// Arms a one-shot `channel` to fire once the main counter reaches `deadline`.
+/// The comparator only fires on an exact match, so this returns false if the deadline has
+/// already passed by the time it was written, in which case the interrupt may never come.
+pub fn hpet_set_oneshot_timer(channel: u8, deadline: u64) -> bool {
+    let channel_offset = 0x20 * channel as u64;
+    hpet_write_64(HPET_REG_TMR_COMP_V + channel_offset, deadline);
+    read_main_counter() < deadline
+}
+
+/// Disarms a one-shot `channel`.
+pub fn hpet_cancel_oneshot_timer(channel: u8) {
+    hpet_write_64(HPET_REG_TMR_COMP_V + 0x20 * channel as u64, u64::MAX);
+}
+
+/// Maps the registers at `base_address`, collects a bunch of information of HPET, sets up
+/// the one-shot timer used by `crate::timer` and starts the main counter.
+pub fn initialize_hpet(base_address: u64) {
+    let registers = unsafe { Mmio::map(PhysAddr::new(base_address), HPET_REGISTERS_SIZE, CacheMode::Uncached) }
+        .expect("Failed to map the HPET!");
+    HPET_REGISTERS.call_once(|| registers);
+
     let period = hpet_read_period();
     let freq: u64 = 1_000_000_000_000_000 / (period as u64);
     // trace!("HPET freq: {}", freq);
 
     //Check general capabilities of HPET
-    let cap_field = unsafe { memory_read_32(HPET_BASE_ADDR.load(Ordering::Relaxed) + HPET_REG_GEN_CAP_ID) };
+    let cap_field = hpet_read_32(HPET_REG_GEN_CAP_ID);
     // trace!("HPET cap field: 0b{:032b}", cap_field);
     let bit64_capable = ((0x1<<13) & cap_field) != 0; //64 bit main counter support
     // trace!("HPET 64 bit capable: {}", bit64_capable);
@@ -138,6 +223,7 @@ pub fn initialize_hpet() {
             period: period,
         };
     }
+    HPET_PERIOD_FS.store(period as u64, Ordering::Relaxed);
 
     unsafe {
         // debug!("0b{:064b}", hpet_read_64(HPET_REG_GEN_CONFIG));
@@ -145,11 +231,11 @@ pub fn initialize_hpet() {
         // debug!("0b{:064b}", hpet_read_64(HPET_REG_GEN_CONFIG));
     }
 
-    //Enable a periodic timer on channel 1
+    //The scheduler tick comes from the local APIC timers, see `interrupts::apic_timer`
+    //Channel 1 drives the kernel timers, see `crate::timer`
     //No need to check if its available, because
     //every system where HPET is supported has a minimum of 3 channels available
-    let irq_freq = 256 as u64; //irq_freq of 2 means 2hz aka twice a second
-    hpet_set_period_timer(0, freq / irq_freq, InterruptIndex::HPET_Timer);
+    hpet_setup_oneshot_timer(crate::timer::HPET_CHANNEL, InterruptIndex::HPET_OneShot);
 
     //Enable the main counter
     unsafe {
@@ -1,6 +1,6 @@
 use cpuio::{inb, outb};
 
-use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicU16, AtomicU64, Ordering};
 
 pub static TICK_COUNT: AtomicU64 = AtomicU64::new(0);
 static TICKS_PER_SECOND: AtomicU16 = AtomicU16::new(0);
@@ -29,7 +29,261 @@ pub unsafe fn enable_rtc(rate: u8) {
     TICKS_PER_SECOND.store(freq, Ordering::SeqCst);
 }
 
+/// Busy waits for `seconds`. Only meant for early boot, before the scheduler runs.
+/// Threads should use `multitasking::sleep` instead.
 pub fn sleep(seconds: f32) {
     let ticks = TICK_COUNT.load(Ordering::SeqCst) + (seconds * TICKS_PER_SECOND.load(Ordering::SeqCst) as f32) as u64;
-    while TICK_COUNT.compare_and_swap(ticks, ticks+1, Ordering::Relaxed) != ticks {}
+    while TICK_COUNT.load(Ordering::SeqCst) < ticks {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Date and time
+///////////////////////////////////////////////////////////////////////////////////////////////////
+const RTC_REG_SECONDS: u8 = 0x00;
+const RTC_REG_SECONDS_ALARM: u8 = 0x01;
+const RTC_REG_MINUTES: u8 = 0x02;
+const RTC_REG_MINUTES_ALARM: u8 = 0x03;
+const RTC_REG_HOURS: u8 = 0x04;
+const RTC_REG_HOURS_ALARM: u8 = 0x05;
+const RTC_REG_DAY: u8 = 0x07;
+const RTC_REG_MONTH: u8 = 0x08;
+const RTC_REG_YEAR: u8 = 0x09;
+const RTC_REG_STATUS_A: u8 = 0x0A;
+const RTC_REG_STATUS_B: u8 = 0x0B;
+const RTC_REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INTERRUPT: u8 = 1 << 5;
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+/// The CMOS register holding the century, taken from the FADT by `set_century_register`.
+/// 0 if there is none, the century is assumed to be 20 then.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+static ALARM_CALLBACK: spin::Mutex<Option<fn()>> = spin::Mutex::new(None);
+
+/// A date and time as stored in the CMOS, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// The raw register values, before decoding.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn cmos_read(reg: u8) -> u8 {
+    outb(reg, 0x70);
+    inb(0x71)
+}
+
+unsafe fn cmos_write(reg: u8, value: u8) {
+    outb(reg, 0x70);
+    outb(value, 0x71);
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Sets the CMOS register holding the century, as reported by the FADT. 0 means there is none.
+pub fn set_century_register(reg: u8) {
+    CENTURY_REGISTER.store(reg, Ordering::Relaxed);
+}
+
+unsafe fn update_in_progress() -> bool {
+    cmos_read(RTC_REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+unsafe fn read_raw() -> RawDateTime {
+    let century_reg = CENTURY_REGISTER.load(Ordering::Relaxed);
+    RawDateTime {
+        second: cmos_read(RTC_REG_SECONDS),
+        minute: cmos_read(RTC_REG_MINUTES),
+        hour: cmos_read(RTC_REG_HOURS),
+        day: cmos_read(RTC_REG_DAY),
+        month: cmos_read(RTC_REG_MONTH),
+        year: cmos_read(RTC_REG_YEAR),
+        century: if century_reg != 0 { cmos_read(century_reg) } else { 0 },
+    }
+}
+
+/// Reads the current date and time from the CMOS.
+///
+/// The registers are read until two reads in a row agree, so an update halfway through
+/// can't give a mix of old and new values.
+pub fn read_date_time() -> DateTime {
+    let (raw, status_b) = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        while update_in_progress() {
+            core::sync::atomic::spin_loop_hint();
+        }
+        let mut raw = read_raw();
+        loop {
+            while update_in_progress() {
+                core::sync::atomic::spin_loop_hint();
+            }
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos_read(RTC_REG_STATUS_B))
+    });
+    decode_date_time(raw, status_b, CENTURY_REGISTER.load(Ordering::Relaxed) != 0)
+}
+
+/// Turns the register values into a date, following the encoding set in status register B.
+fn decode_date_time(raw: RawDateTime, status_b: u8, has_century: bool) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    // In 12 hour mode the PM flag sits in the top bit of the hour, on top of the encoding
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        let pm = raw.hour & HOUR_PM != 0;
+        hour = match (hour, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (hour, true) => hour + 12,
+            (hour, false) => hour,
+        };
+    }
+
+    let century = if has_century {
+        decode(raw.century) as u16
+    } else {
+        20 //Good enough for a while
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Alarm
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Calls `callback` every day at the given time, from the RTC interrupt.
+/// `None` matches any value, so `set_alarm(None, None, Some(0), f)` fires every minute.
+pub fn set_alarm(hour: Option<u8>, minute: Option<u8>, second: Option<u8>, callback: fn()) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let status_b = cmos_read(RTC_REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let encode = |value: Option<u8>| match value {
+            Some(value) if binary => value,
+            Some(value) => to_bcd(value),
+            None => 0xC0, //"Don't care"
+        };
+
+        // Alarm hours use the same 12 hour format as the clock itself
+        let alarm_hour = match hour {
+            Some(hour) if status_b & STATUS_B_24_HOUR == 0 => {
+                let pm = if hour >= 12 { HOUR_PM } else { 0 };
+                let hour_12 = match hour % 12 { 0 => 12, hour => hour };
+                encode(Some(hour_12)) | pm
+            },
+            hour => encode(hour),
+        };
+
+        *ALARM_CALLBACK.lock() = Some(callback);
+        cmos_write(RTC_REG_SECONDS_ALARM, encode(second));
+        cmos_write(RTC_REG_MINUTES_ALARM, encode(minute));
+        cmos_write(RTC_REG_HOURS_ALARM, alarm_hour);
+        cmos_write(RTC_REG_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+    });
+}
+
+/// Disables the alarm interrupt.
+pub fn clear_alarm() {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let status_b = cmos_read(RTC_REG_STATUS_B);
+        cmos_write(RTC_REG_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+        *ALARM_CALLBACK.lock() = None;
+    });
+}
+
+/// Acknowledges an RTC interrupt, counts periodic ticks and runs the alarm callback if that's
+/// what fired.
+/// Called from the RTC interrupt handler. Status register C has to be read after
+/// every interrupt, otherwise the RTC won't raise another one.
+pub fn handle_interrupt() {
+    let status_c = unsafe { cmos_read(RTC_REG_STATUS_C) };
+    if status_c & STATUS_C_PERIODIC != 0 {
+        TICK_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+    if status_c & STATUS_C_ALARM != 0 {
+        let callback = *ALARM_CALLBACK.lock();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[test_case]
+fn test_bcd() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(from_bcd(0x00), 0);
+    assert_eq!(to_bcd(42), 0x42);
+    for value in 0..100 {
+        assert_eq!(from_bcd(to_bcd(value)), value);
+    }
+}
+
+#[test_case]
+fn test_decode_date_time() {
+    let raw = RawDateTime { second: 0x30, minute: 0x15, hour: 0x23, day: 0x29, month: 0x02, year: 0x24, century: 0x20 };
+    let expected = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 15, second: 30 };
+    assert_eq!(decode_date_time(raw, STATUS_B_24_HOUR, true), expected);
+
+    // Binary mode, without a century register
+    let raw = RawDateTime { second: 30, minute: 15, hour: 23, day: 29, month: 2, year: 24, century: 0xFF };
+    assert_eq!(decode_date_time(raw, STATUS_B_24_HOUR | STATUS_B_BINARY, false), expected);
+}
+
+#[test_case]
+fn test_decode_12_hour_mode() {
+    let hour = |raw_hour: u8, status_b: u8| {
+        let raw = RawDateTime { second: 0, minute: 0, hour: raw_hour, day: 1, month: 1, year: 0, century: 0x20 };
+        decode_date_time(raw, status_b, true).hour
+    };
+    // BCD
+    assert_eq!(hour(0x12, 0), 0); //12 AM
+    assert_eq!(hour(0x01, 0), 1);
+    assert_eq!(hour(0x12 | HOUR_PM, 0), 12); //12 PM
+    assert_eq!(hour(0x11 | HOUR_PM, 0), 23);
+    // Binary, the PM flag is still the top bit
+    assert_eq!(hour(12, STATUS_B_BINARY), 0);
+    assert_eq!(hour(11 | HOUR_PM, STATUS_B_BINARY), 23);
 }
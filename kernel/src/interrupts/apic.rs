@@ -1,26 +1,129 @@
 use cpuio::outb;
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use crate::memory::{memory_read_32, memory_write_32};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::memory::mmio::{CacheMode, Mmio};
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // APIC
 ///////////////////////////////////////////////////////////////////////////////////////////////////
-const APIC_ADDRESS: u64 = 0xFEE00000; //TODO: Get this from ACPI table although it shouldn't change
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// In x2APIC mode register `offset` is MSR `X2APIC_MSR_BASE + offset / 0x10`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Size of the xAPIC register page.
+const LAPIC_REGISTERS_SIZE: u64 = 0x400;
 
-const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_REG_ID: u64 = 0x20;
+pub(super) const LAPIC_LVT_TIMER: u64 = 0x320;
 const LAPIC_LVT_THERMAL_SENSOR: u64 = 0x330;
 const LAPIC_LVT_PERFORMANCE_MONITORING: u64 = 0x340;
 const LAPIC_LVT_LINT0: u64 = 0x350;
 const LAPIC_LVT_LINT1: u64 = 0x360;
 const LAPIC_LVT_ERROR: u64 = 0x370;
-const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
-const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
-const LAPIC_TIMER_DIVIDE_CONFIG: u64 = 0x3E0;
+pub(super) const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+pub(super) const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+pub(super) const LAPIC_TIMER_DIVIDE_CONFIG: u64 = 0x3E0;
+const LAPIC_SPURIOUS_VECTOR: u64 = 0xF0;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_ISR: u64 = 0x100; //8 registers of 32 bits each, 0x10 apart
+const LAPIC_IRR: u64 = 0x200;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Vector the local APIC raises when an interrupt goes away before it could be delivered.
+/// The low 4 bits are hardwired to 1 on some older APICs, so it has to end in 0xF.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// The xAPIC registers, not mapped in x2APIC mode where they're accessed through MSRs.
+/// Every CPU sees its own local APIC behind the same physical address.
+static LAPIC_REGISTERS: spin::Once<Mmio> = spin::Once::new();
+static X2APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Finds the local APIC and decides how it's accessed. Has to run on the BSP before anything
+/// touches a local APIC register, and after `memory::init_kernel_p4_entries`.
+///
+/// Uses x2APIC mode if the CPU supports it, otherwise maps the registers uncached.
+/// `madt_address` is the address the MADT reports, the MSR wins if they disagree.
+pub unsafe fn init_lapic(madt_address: u64) {
+    let base = Msr::new(IA32_APIC_BASE).read();
+    let phys_addr = base & APIC_BASE_ADDRESS_MASK;
+    if phys_addr != madt_address {
+        warn!("[APIC] MADT says the local APIC is at {:#X}, but it's at {:#X}", madt_address, phys_addr);
+    }
 
-pub fn get_apic_address(apic_id: u8) -> u64 {
-    APIC_ADDRESS + 0x10 * (apic_id as u64) //I think actually every core has it mapped to the same address, so maybe its irrelevant what the id is here
+    let has_x2apic = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |features| features.has_x2apic());
+    if has_x2apic {
+        X2APIC_ENABLED.store(true, Ordering::SeqCst);
+        switch_to_x2apic();
+        debug!("[APIC] Using x2APIC mode");
+    } else {
+        let registers = Mmio::map(PhysAddr::new(phys_addr), LAPIC_REGISTERS_SIZE, CacheMode::Uncached)
+            .expect("Failed to map the local APIC!");
+        LAPIC_REGISTERS.call_once(|| registers);
+        debug!("[APIC] Local APIC at {:#X}", phys_addr);
+    }
+}
+
+/// Puts the calling CPU's local APIC into x2APIC mode, which every CPU has to do on its own.
+unsafe fn switch_to_x2apic() {
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let value = base.read();
+    if value & APIC_BASE_X2APIC_ENABLE == 0 {
+        // xAPIC mode has to be on before x2APIC mode can be turned on
+        base.write(value | APIC_BASE_GLOBAL_ENABLE);
+        base.write(value | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE);
+    }
+}
+
+pub fn x2apic_enabled() -> bool {
+    X2APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads a register of the calling CPU's local APIC, `offset` is the xAPIC MMIO offset.
+pub(super) unsafe fn lapic_read(offset: u64) -> u32 {
+    if x2apic_enabled() {
+        Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).read() as u32
+    } else {
+        lapic_registers().read_u32(offset)
+    }
+}
+
+pub(super) unsafe fn lapic_write(offset: u64, value: u32) {
+    if x2apic_enabled() {
+        Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).write(value as u64);
+    } else {
+        lapic_registers().write_u32(offset, value);
+    }
+}
+
+fn lapic_registers() -> &'static Mmio {
+    LAPIC_REGISTERS.r#try().expect("Local APIC accessed before `init_lapic`!")
+}
+
+/// Returns the APIC id of the calling CPU. Works before `enable_apic`, through CPUID.
+pub fn local_apic_id() -> u32 {
+    let cpuid = raw_cpuid::CpuId::new();
+    if x2apic_enabled() {
+        // 32 bit id, the one in leaf 1 is cut off
+        if let Some(level) = cpuid.get_extended_topology_info().and_then(|mut levels| levels.next()) {
+            return level.x2apic_id();
+        }
+    } else if LAPIC_REGISTERS.r#try().is_some() {
+        return unsafe { lapic_read(LAPIC_REG_ID) } >> 24;
+    }
+    cpuid.get_feature_info().map_or(0, |features| features.initial_local_apic_id() as u32)
 }
 
 pub unsafe fn disable_pic() {
@@ -45,43 +148,182 @@ pub unsafe fn disable_pic() {
     outb(0xff, 0xa1);
 }
 
-pub unsafe fn enable_apic(apic_id: u8) {
-    let apic_addr = get_apic_address(apic_id);
-    let mut val = memory_read_32(apic_addr + 0xF0);
-    val |= (1<<8);
-    memory_write_32(apic_addr + 0xF0, val);
+/// Enables the local APIC of the calling CPU and points its spurious interrupts at
+/// `SPURIOUS_VECTOR`. Switches the CPU to x2APIC mode first if `init_lapic` picked it.
+pub unsafe fn enable_apic() {
+    if x2apic_enabled() {
+        switch_to_x2apic();
+    }
+
+    let mut val = lapic_read(LAPIC_SPURIOUS_VECTOR);
+    val &= !0xFF;
+    val |= APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32;
+    lapic_write(LAPIC_SPURIOUS_VECTOR, val);
 }
 
-pub unsafe fn apic_send_eoi(apic_id: u8) {
-    let apic_addr = get_apic_address(apic_id);
-    memory_write_32(apic_addr + 0xB0, 0);
+pub unsafe fn apic_send_eoi() {
+    lapic_write(LAPIC_EOI, 0);
 }
 
-pub unsafe fn apic_set_timer_mask(apic_addr: u64, mask: bool) {
-    let mut entry: u32 = memory_read_32(apic_addr + LAPIC_LVT_TIMER);
+pub unsafe fn apic_set_timer_mask(mask: bool) {
+    let mut entry: u32 = lapic_read(LAPIC_LVT_TIMER);
     if mask {
         entry |= (1 as u32) << 16;
     } else {
         entry &= !((1 as u32) << 16);
     }
-    memory_write_32(apic_addr + LAPIC_LVT_TIMER, entry);
+    lapic_write(LAPIC_LVT_TIMER, entry);
 }
 
-/// Doesn't work lol
-pub unsafe fn apic_set_timer(apic_id: u8) {
-    let apic_addr = get_apic_address(apic_id);
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Interrupt state
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Reads one of the 256 bit vector registers (ISR, TMR, IRR) of the current CPU.
+fn read_vector_bitmap(base: u64) -> [u32; 8] {
+    let mut bitmap = [0; 8];
+    for (i, word) in bitmap.iter_mut().enumerate() {
+        *word = unsafe { lapic_read(base + 0x10 * i as u64) };
+    }
+    bitmap
+}
+
+fn vector_bit_set(base: u64, vector: u8) -> bool {
+    let word = unsafe { lapic_read(base + 0x10 * (vector as u64 / 32)) };
+    word & (1 << (vector % 32)) != 0
+}
+
+/// Returns true if `vector` was delivered to this CPU and hasn't been EOI'd yet.
+pub fn is_in_service(vector: u8) -> bool {
+    vector_bit_set(LAPIC_ISR, vector)
+}
+
+/// Returns true if `vector` was accepted by this CPU but not delivered yet.
+pub fn is_pending(vector: u8) -> bool {
+    vector_bit_set(LAPIC_IRR, vector)
+}
+
+/// The in-service vector with the highest priority, which is the one the next EOI acknowledges.
+pub fn highest_in_service() -> Option<u8> {
+    let isr = read_vector_bitmap(LAPIC_ISR);
+    isr.iter().enumerate().rev()
+        .find(|(_, &word)| word != 0)
+        .map(|(i, &word)| (i * 32 + 31 - word.leading_zeros() as usize) as u8)
+}
+
+/// Logs the in-service and pending vectors of this CPU, for tracking down IRQs that got stuck.
+pub fn log_interrupt_state() {
+    let isr = read_vector_bitmap(LAPIC_ISR);
+    let irr = read_vector_bitmap(LAPIC_IRR);
+    for vector in 0..=255u8 {
+        let word = vector as usize / 32;
+        let bit = 1 << (vector % 32);
+        if isr[word] & bit != 0 || irr[word] & bit != 0 {
+            debug!(
+                "[APIC] Vector {:#X}: in service: {}, pending: {}",
+                vector, isr[word] & bit != 0, irr[word] & bit != 0
+            );
+        }
+    }
+    debug!("[APIC] {} spurious interrupts so far", spurious_interrupt_count());
+}
+
+/// Called from the `SPURIOUS_VECTOR` handler. Spurious interrupts are never in service,
+/// so they must not be EOI'd.
+pub(super) fn record_spurious_interrupt() {
+    SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn spurious_interrupt_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IPIs
+///////////////////////////////////////////////////////////////////////////////////////////////////
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// Who receives an IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// A single CPU, by APIC id
+    Apic(u32),
+    SelfOnly,
+    /// Every CPU, including the sender
+    All,
+    AllButSelf,
+}
 
-    apic_set_timer_mask(apic_addr, true); //Disable the timer
+/// What an IPI makes the receiving CPUs do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    /// Raises the interrupt with this vector
+    Fixed(u8),
+    Nmi,
+    /// Resets the CPU into the wait-for-SIPI state
+    Init,
+    /// Starts a CPU waiting for a SIPI in real mode at `page * 0x1000`
+    Startup(u8),
+}
 
-    memory_write_32(apic_addr + LAPIC_TIMER_DIVIDE_CONFIG, 0x3); //0x3 = 011 = 16, divider
-    memory_write_32(apic_addr + LAPIC_TIMER_INITIAL_COUNT, 0xFFFF_FFFF); //Reset initial count to -1
+impl IpiKind {
+    fn icr_bits(self) -> u32 {
+        match self {
+            IpiKind::Fixed(vector) => ICR_DELIVERY_FIXED | vector as u32,
+            IpiKind::Nmi => ICR_DELIVERY_NMI,
+            IpiKind::Init => ICR_DELIVERY_INIT,
+            IpiKind::Startup(page) => ICR_DELIVERY_STARTUP | page as u32,
+        }
+    }
+}
 
-    apic_set_timer_mask(apic_addr, false); //Enable timer
-    crate::hardware::rtc::sleep(0.01); //Sleep for 10ms
-    apic_set_timer_mask(apic_addr, true); //Disable timer
+/// Sends an IPI through the local APIC of the current CPU and waits until it was delivered.
+/// Unsafe because INIT, startup and NMI IPIs can take down CPUs that aren't expecting them.
+pub unsafe fn send_ipi(destination: IpiDestination, kind: IpiKind) {
+    let (apic_id, shorthand) = match destination {
+        IpiDestination::Apic(apic_id) => (apic_id, 0),
+        IpiDestination::SelfOnly => (0, ICR_SHORTHAND_SELF),
+        IpiDestination::All => (0, ICR_SHORTHAND_ALL),
+        IpiDestination::AllButSelf => (0, ICR_SHORTHAND_ALL_BUT_SELF),
+    };
+    let low = shorthand | ICR_LEVEL_ASSERT | kind.icr_bits();
+    if x2apic_enabled() {
+        // A single 64 bit register with the full 32 bit APIC id on top
+        let icr = (apic_id as u64) << 32 | low as u64;
+        Msr::new(X2APIC_MSR_BASE + (LAPIC_ICR_LOW >> 4) as u32).write(icr);
+        return;
+    }
+    // An interrupt between the two writes could send an IPI of its own and change the destination
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        wait_for_ipi_delivery();
+        lapic_write(LAPIC_ICR_HIGH, apic_id << 24);
+        lapic_write(LAPIC_ICR_LOW, low);
+        wait_for_ipi_delivery();
+    });
+}
 
-    let ticks = memory_read_32(apic_addr + LAPIC_TIMER_CURRENT_COUNT);
+/// Returns true while the last IPI sent by this CPU hasn't been accepted yet.
+/// x2APICs don't report this, their ICR writes only complete once the IPI is on its way.
+pub fn ipi_delivery_pending() -> bool {
+    if x2apic_enabled() {
+        return false;
+    }
+    unsafe { lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 }
+}
 
-    trace!("apic timer ticks in 10ms");
-    trace!("{}", ticks);
+pub fn wait_for_ipi_delivery() {
+    while ipi_delivery_pending() {
+        core::sync::atomic::spin_loop_hint();
+    }
 }
@@ -0,0 +1,186 @@
+use cpuio::{inb, outb};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::registers::model_specific::Msr;
+
+use super::apic::{
+    apic_set_timer_mask,
+    lapic_read,
+    lapic_write,
+    LAPIC_LVT_TIMER,
+    LAPIC_TIMER_INITIAL_COUNT,
+    LAPIC_TIMER_CURRENT_COUNT,
+    LAPIC_TIMER_DIVIDE_CONFIG,
+};
+use super::InterruptIndex;
+
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// How long the timer is measured for during calibration.
+const CALIBRATION_MS: u64 = 10;
+
+/// LAPIC timer and TSC ticks per millisecond, measured by `calibrate`.
+/// The LAPIC timer runs at the same rate on every core, so this is only done once.
+static LAPIC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+static TSC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Mode bits of the LVT timer register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ApicTimerMode {
+    OneShot = 0b00 << 17,
+    Periodic = 0b01 << 17,
+    TscDeadline = 0b10 << 17,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Calibration
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Measures the LAPIC timer and the TSC against the HPET, or the PIT if the HPET isn't set up.
+pub unsafe fn calibrate() {
+    let use_hpet = crate::hardware::hpet::is_initialized();
+
+    set_lvt_timer(ApicTimerMode::OneShot, InterruptIndex::LapicTimer.as_u8(), true);
+    lapic_write(LAPIC_TIMER_DIVIDE_CONFIG, LAPIC_TIMER_DIVIDE_BY_16);
+
+    let tsc_start = crate::hardware::rdtsc::read_rdtsc();
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, 0xFFFF_FFFF); //Starts counting down
+    if use_hpet {
+        hpet_wait_ms(CALIBRATION_MS);
+    } else {
+        pit_wait_ms(CALIBRATION_MS);
+    }
+    let remaining = lapic_read(LAPIC_TIMER_CURRENT_COUNT);
+    let tsc_end = crate::hardware::rdtsc::read_rdtsc();
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, 0); //Stop it again
+
+    let lapic_ticks = (0xFFFF_FFFF - remaining) as u64 / CALIBRATION_MS;
+    let tsc_ticks = (tsc_end - tsc_start) / CALIBRATION_MS;
+    LAPIC_TICKS_PER_MS.store(lapic_ticks, Ordering::Relaxed);
+    TSC_TICKS_PER_MS.store(tsc_ticks, Ordering::Relaxed);
+    debug!(
+        "[APIC] Timer calibrated against {}: {} ticks/ms, TSC {} ticks/ms",
+        if use_hpet { "HPET" } else { "PIT" }, lapic_ticks, tsc_ticks
+    );
+}
+
+fn hpet_wait_ms(ms: u64) {
+    crate::hardware::hpet::busy_wait(Duration::from_millis(ms));
+}
+
+/// Busy waits using channel 2 of the PIT, which is gated through port 0x61 and doesn't raise
+/// an interrupt. `ms` has to be at most 54.
+unsafe fn pit_wait_ms(ms: u64) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count <= 0xFFFF, "PIT can't wait that long");
+
+    // Gate channel 2 off and the speaker off while programming it
+    let gate = inb(0x61) & !0b11;
+    outb(gate, 0x61);
+    outb(0b1011_0000, 0x43); //Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    outb(count as u8, 0x42);
+    outb((count >> 8) as u8, 0x42);
+    outb(gate | 0b01, 0x61); //Start counting
+
+    //Bit 5 is the output of channel 2, which goes high once the count reaches 0
+    while inb(0x61) & 0x20 == 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    outb(gate, 0x61);
+}
+
+pub fn is_calibrated() -> bool {
+    LAPIC_TICKS_PER_MS.load(Ordering::Relaxed) != 0
+}
+
+fn lapic_ticks(duration: Duration) -> u32 {
+    let per_ms = LAPIC_TICKS_PER_MS.load(Ordering::Relaxed) as u128;
+    assert!(per_ms != 0, "APIC timer is not calibrated!");
+    let ticks = duration.as_nanos() * per_ms / 1_000_000;
+    core::cmp::max(1, core::cmp::min(ticks, u32::MAX as u128)) as u32
+}
+
+/// Returns the TSC frequency in Hz, measured during calibration.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_TICKS_PER_MS.load(Ordering::Relaxed) {
+        0 => None,
+        per_ms => Some(per_ms * 1000),
+    }
+}
+
+/// Converts a duration to TSC ticks, for use with `set_tsc_deadline`.
+pub fn tsc_ticks(duration: Duration) -> u64 {
+    let per_ms = TSC_TICKS_PER_MS.load(Ordering::Relaxed) as u128;
+    assert!(per_ms != 0, "APIC timer is not calibrated!");
+    (duration.as_nanos() * per_ms / 1_000_000) as u64
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Timer modes
+///////////////////////////////////////////////////////////////////////////////////////////////////
+unsafe fn set_lvt_timer(mode: ApicTimerMode, vector: u8, masked: bool) {
+    let mut entry = mode as u32 | vector as u32;
+    if masked {
+        entry |= 1 << 16;
+    }
+    lapic_write(LAPIC_LVT_TIMER, entry);
+}
+
+/// Returns true if this CPU can fire the timer at an absolute TSC value.
+pub fn supports_tsc_deadline() -> bool {
+    raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |features| features.has_tsc_deadline())
+}
+
+/// Fires `vector` every `period` on the calling core.
+pub unsafe fn set_periodic(vector: u8, period: Duration) {
+    set_lvt_timer(ApicTimerMode::Periodic, vector, true);
+    lapic_write(LAPIC_TIMER_DIVIDE_CONFIG, LAPIC_TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, lapic_ticks(period));
+    apic_set_timer_mask(false);
+}
+
+/// Fires `vector` once, after `delay`, on the calling core.
+pub unsafe fn set_oneshot(vector: u8, delay: Duration) {
+    set_lvt_timer(ApicTimerMode::OneShot, vector, false);
+    lapic_write(LAPIC_TIMER_DIVIDE_CONFIG, LAPIC_TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, lapic_ticks(delay));
+}
+
+/// Fires `vector` once the TSC of the calling core reaches `deadline`.
+/// Check `supports_tsc_deadline` first.
+pub unsafe fn set_tsc_deadline(vector: u8, deadline: u64) {
+    set_lvt_timer(ApicTimerMode::TscDeadline, vector, false);
+    // The mode switch has to be visible before the MSR write, see the Intel SDM 10.5.4.1
+    core::sync::atomic::fence(Ordering::SeqCst);
+    Msr::new(IA32_TSC_DEADLINE).write(deadline);
+}
+
+/// Stops the timer of the calling core, whatever mode it's in.
+pub unsafe fn stop() {
+    apic_set_timer_mask(true);
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+    if supports_tsc_deadline() {
+        Msr::new(IA32_TSC_DEADLINE).write(0);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Scheduler tick
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Starts the scheduler tick on the calling core. Calibrates the timer first if that hasn't
+/// happened yet.
+pub unsafe fn start_scheduler_tick() {
+    if !is_calibrated() {
+        calibrate();
+    }
+    let period = Duration::from_nanos(1_000_000_000 / crate::multitasking::TICKS_PER_SECOND);
+    set_periodic(InterruptIndex::LapicTimer.as_u8(), period);
+}
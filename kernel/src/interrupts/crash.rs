@@ -0,0 +1,206 @@
+use core::fmt;
+use core::mem;
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+global_asm!(include_str!("crash.s"));
+
+/// Entry stubs in `crash.s`. Only their addresses end up in the IDT, see `stub`.
+extern "C" {
+    pub(super) fn asm_divide_error_entry();
+    pub(super) fn asm_debug_entry();
+    pub(super) fn asm_nmi_entry();
+    pub(super) fn asm_overflow_entry();
+    pub(super) fn asm_bound_range_exceeded_entry();
+    pub(super) fn asm_invalid_opcode_entry();
+    pub(super) fn asm_device_not_available_entry();
+    pub(super) fn asm_double_fault_entry();
+    pub(super) fn asm_invalid_tss_entry();
+    pub(super) fn asm_segment_not_present_entry();
+    pub(super) fn asm_stack_segment_fault_entry();
+    pub(super) fn asm_general_protection_entry();
+    pub(super) fn asm_page_fault_entry();
+    pub(super) fn asm_x87_floating_point_entry();
+    pub(super) fn asm_alignment_check_entry();
+    pub(super) fn asm_machine_check_entry();
+    pub(super) fn asm_simd_floating_point_entry();
+}
+
+/// Makes a stub from `crash.s` look like the handler type `F` the IDT entry wants. The stubs
+/// call the handler themselves, with a `CrashFrame` instead of what `F` would get.
+pub(super) unsafe fn stub<F: Copy>(entry: unsafe extern "C" fn()) -> F {
+    assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>(), "not a function pointer");
+    mem::transmute_copy(&entry)
+}
+
+/// What the stubs in `crash.s` pass to the handlers. Exceptions without an error code get 0.
+#[repr(C)]
+pub(super) struct CrashFrame {
+    pub registers: GeneralRegisters,
+    pub vector: u64,
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+/// The general purpose registers of the interrupted code, in the order the stubs push them.
+/// RSP is part of the interrupt stack frame.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(super) struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX: {:#018X}  RBX: {:#018X}  RCX: {:#018X}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX: {:#018X}  RSI: {:#018X}  RDI: {:#018X}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP: {:#018X}  R8:  {:#018X}  R9:  {:#018X}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10: {:#018X}  R11: {:#018X}  R12: {:#018X}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13: {:#018X}  R14: {:#018X}  R15: {:#018X}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Everything known about the CPU state when an exception hit, printed when the kernel panics
+/// because of it: the registers the stub saved, the interrupt stack frame, the control registers
+/// and whatever the exception reports on top.
+pub(super) struct CrashReport<'a> {
+    pub exception: &'static str,
+    pub frame: &'a CrashFrame,
+    pub error_code: Option<u64>,
+    pub control_registers: ControlRegisters,
+    /// A register that says more about this exception, like MXCSR for SIMD exceptions
+    pub detail: Option<(&'static str, u64)>,
+}
+
+impl<'a> CrashReport<'a> {
+    /// Takes a snapshot of the control registers, so call it first thing in the handler.
+    pub fn new(exception: &'static str, frame: &'a CrashFrame) -> Self {
+        CrashReport {
+            exception,
+            frame,
+            error_code: None,
+            control_registers: ControlRegisters::read(),
+            detail: None,
+        }
+    }
+
+    /// Includes the error code in the report, for exceptions that push one.
+    pub fn with_error_code(mut self) -> Self {
+        self.error_code = Some(self.frame.error_code);
+        self
+    }
+
+    pub fn with_detail(mut self, name: &'static str, value: u64) -> Self {
+        self.detail = Some((name, value));
+        self
+    }
+
+    pub fn stack_frame(&self) -> &'a InterruptStackFrame {
+        &self.frame.stack_frame
+    }
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.frame.stack_frame;
+        writeln!(f, "EXCEPTION: {} (vector {})", self.exception, self.frame.vector)?;
+        writeln!(f, "{}", FaultLocation)?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "Error Code: {:#X}", error_code)?;
+        }
+        if let Some((name, value)) = self.detail {
+            writeln!(f, "{}: {:#X}", name, value)?;
+        }
+        writeln!(f, "RIP: {:#018X}  CS: {:#06X}", frame.instruction_pointer.as_u64(), frame.code_segment)?;
+        writeln!(f, "RSP: {:#018X}  SS: {:#06X}", frame.stack_pointer.as_u64(), frame.stack_segment)?;
+        writeln!(f, "RFLAGS: {:#018X}", frame.cpu_flags)?;
+        writeln!(f, "{}", self.frame.registers)?;
+        write!(f, "{}", self.control_registers)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ControlRegisters {
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl ControlRegisters {
+    fn read() -> Self {
+        let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        ControlRegisters { cr0, cr2, cr3, cr4 }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CR0: {:#018X}  CR2: {:#018X}", self.cr0, self.cr2)?;
+        write!(f, "CR3: {:#018X}  CR4: {:#018X}", self.cr3, self.cr4)
+    }
+}
+
+/// Where a kernel fault happened.
+struct FaultLocation;
+
+impl fmt::Display for FaultLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percpu = crate::smp::percpu::current();
+        match percpu.current_thread() {
+            Some(thread_id) => write!(f, "CPU {}, thread {}", percpu.cpu_index(), thread_id.as_u64()),
+            None => write!(f, "CPU {}, before the scheduler started", percpu.cpu_index()),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Exception specific registers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+const IA32_MCG_STATUS: u32 = 0x17A;
+
+/// Status word of the x87 FPU, says which x87 exception happened.
+pub(super) fn x87_status_word() -> u64 {
+    let status: u16;
+    unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack, preserves_flags)); }
+    status as u64
+}
+
+/// SIMD control and status register, its low 6 bits say which SIMD exception happened.
+pub(super) fn mxcsr() -> u64 {
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)); }
+    mxcsr as u64
+}
+
+/// Debug status register, says which breakpoint or single step condition hit.
+pub(super) fn dr6() -> u64 {
+    let dr6: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags)); }
+    dr6
+}
+
+/// Global machine check status. Bit 0 says whether execution can be restarted.
+pub(super) fn mcg_status() -> u64 {
+    unsafe { x86_64::registers::model_specific::Msr::new(IA32_MCG_STATUS).read() }
+}
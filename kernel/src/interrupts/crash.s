@@ -0,0 +1,56 @@
+//; in src/interrupts/crash.s
+//; use intel asm syntax
+.intel_syntax noprefix
+
+//; Entry stubs of the exceptions that end in a crash report, see `interrupts/crash.rs`.
+//; They build a `CrashFrame` on top of the interrupt stack frame and call the handler with it.
+//; The handlers never return, so nothing gets restored.
+//;
+//; The CPU aligns the stack to 16 bytes before pushing its frame. The frame, the error code,
+//; the vector and the 15 registers add up to 176 bytes, so the stack is aligned for the call.
+.macro EXCEPTION_STUB name, handler, vector, has_error_code
+.global \name
+\name:
+    .if \has_error_code == 0
+    push 0                      //; same layout for exceptions without an error code
+    .endif
+    push \vector
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp                //; pass a pointer to the frame as argument
+    cld
+    call \handler
+    ud2
+.endm
+
+EXCEPTION_STUB asm_divide_error_entry,          divide_error_handler,           0,  0
+EXCEPTION_STUB asm_debug_entry,                 debug_handler,                  1,  0
+EXCEPTION_STUB asm_nmi_entry,                   nmi_handler,                    2,  0
+EXCEPTION_STUB asm_overflow_entry,              overflow_handler,               4,  0
+EXCEPTION_STUB asm_bound_range_exceeded_entry,  bound_range_exceeded_handler,   5,  0
+EXCEPTION_STUB asm_invalid_opcode_entry,        invalid_opcode_handler,         6,  0
+EXCEPTION_STUB asm_device_not_available_entry,  device_not_available_handler,   7,  0
+EXCEPTION_STUB asm_double_fault_entry,          double_fault_handler,           8,  1
+EXCEPTION_STUB asm_invalid_tss_entry,           invalid_tss_handler,            10, 1
+EXCEPTION_STUB asm_segment_not_present_entry,   segment_not_present_handler,    11, 1
+EXCEPTION_STUB asm_stack_segment_fault_entry,   stack_segment_fault_handler,    12, 1
+EXCEPTION_STUB asm_general_protection_entry,    general_protection_fault_handler, 13, 1
+EXCEPTION_STUB asm_page_fault_entry,            page_fault_handler,             14, 1
+EXCEPTION_STUB asm_x87_floating_point_entry,    x87_floating_point_handler,     16, 0
+EXCEPTION_STUB asm_alignment_check_entry,       alignment_check_handler,        17, 1
+EXCEPTION_STUB asm_machine_check_entry,         machine_check_handler,          18, 0
+EXCEPTION_STUB asm_simd_floating_point_entry,   simd_floating_point_handler,    19, 0
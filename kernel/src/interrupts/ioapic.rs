@@ -1,73 +1,195 @@
 use alloc::vec::Vec;
 use spin::Mutex;
 
-use acpi::platform::IoApic;
+use x86_64::PhysAddr;
 
-use crate::memory::{memory_read_32, memory_write_32};
+use crate::memory::mmio::{CacheMode, Mmio};
+
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_REGISTERS_SIZE: u64 = 0x20;
+
+const IOAPIC_REG_ID: u32 = 0x00;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION: u32 = 0x10; //Two registers per entry, low half first
+
+const REDIRECTION_VECTOR: u32 = 0xFF;
+const REDIRECTION_DELIVERY_MODE: u32 = 0x700;
+const REDIRECTION_LOGICAL: u32 = 1 << 11;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
 
 lazy_static! {
-    pub static ref IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+    static ref IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
 }
 
-pub fn get_io_apic_addr(id: u32, index: u32) -> u64 {
-    IOAPICS.lock().iter().nth(id as usize).expect("Failed to get IOAPIC!").address as u64
+/// Electrical properties of an interrupt line, set per redirection entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineMode {
+    pub active_low: bool,
+    pub level_triggered: bool,
 }
 
-pub unsafe fn ioapic_read(id: u32, index: u32) -> u32 {
-    let addr = get_io_apic_addr(id, index);
-    // Write the index to the index register
-    memory_write_32(addr, index);
-    // Read the value from the data register
-    memory_read_32(addr + 0x10)
+impl LineMode {
+    /// ISA interrupts are active high and edge triggered unless an ISO says otherwise.
+    pub const ISA: LineMode = LineMode { active_low: false, level_triggered: false };
+    /// PCI interrupts are active low and level triggered.
+    pub const PCI: LineMode = LineMode { active_low: true, level_triggered: true };
 }
 
-pub unsafe fn ioapic_write(id: u32, index: u32, value: u32) {
-    let addr = get_io_apic_addr(id, index);
-    // Write the index to the index register
-    memory_write_32(addr, index);
-    // Write the value to the data register
-    memory_write_32(addr + 0x10, value);
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IOAPIC
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A single IOAPIC, handling the GSIs `gsi_base..gsi_base + entry_count`.
+#[derive(Debug)]
+pub struct IoApic {
+    id: u8,
+    registers: Mmio,
+    gsi_base: u32,
+    entry_count: u32,
 }
 
-pub fn get_io_apic_index(irq: u32) -> u32 {
-    let mut id = 0;
-    for ioapic in IOAPICS.lock().iter() {
-        if irq as u32 >= ioapic.global_system_interrupt_base {
-            //Found the right IOAPIC (I think)
-            return id;
-        }
-        id += 1;
+impl IoApic {
+    /// Maps the registers and reads the number of redirection entries from the version register.
+    pub unsafe fn new(info: &acpi::platform::IoApic) -> Self {
+        let registers = Mmio::map(PhysAddr::new(info.address as u64), IOAPIC_REGISTERS_SIZE, CacheMode::Uncached)
+            .expect("Failed to map IOAPIC!");
+        let mut ioapic = IoApic {
+            id: info.id,
+            registers,
+            gsi_base: info.global_system_interrupt_base,
+            entry_count: 0,
+        };
+        //Bits 16..24 hold the index of the last entry
+        ioapic.entry_count = ((ioapic.read(IOAPIC_REG_VERSION) >> 16) & 0xFF) + 1;
+        ioapic
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    pub fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    /// Whether `gsi` is one of the inputs of this IOAPIC.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entry_count
+    }
+
+    /// The index and data registers are a pair, so callers must not race each other.
+    /// `IOAPICS` being locked takes care of that.
+    unsafe fn read(&self, index: u32) -> u32 {
+        self.registers.write_u32(IOAPIC_REGSEL, index);
+        self.registers.read_u32(IOAPIC_WINDOW)
     }
-    panic!("Can't find a matching IOAPIC!");
-}
 
-/// Set an IRQ on the IOAPIC
-pub unsafe fn ioapic_set_irq(irq: u32, apic_id: u32, vector: u8) {
-    let ioapic_id = get_io_apic_index(irq);
+    unsafe fn write(&self, index: u32, value: u32) {
+        self.registers.write_u32(IOAPIC_REGSEL, index);
+        self.registers.write_u32(IOAPIC_WINDOW, value);
+    }
 
-    let low_index: u32 = 0x10 + (irq as u32)*2;
-    let high_index: u32 = 0x10 + (irq as u32)*2 + 1;
+    /// Index of the low half of the redirection entry for `gsi`.
+    fn redirection_index(&self, gsi: u32) -> u32 {
+        debug_assert!(self.handles(gsi));
+        IOAPIC_REG_REDIRECTION + (gsi - self.gsi_base) * 2
+    }
 
-    let mut high = ioapic_read(ioapic_id, high_index);
-    // Set APIC ID
-    high &= !0xff000_000;
-    high |= apic_id << 24;
-    ioapic_write(ioapic_id, high_index, high);
+    /// Sends `gsi` to `vector` on the local APIC `apic_id` with fixed delivery and unmasks it.
+    /// The destination field only has 8 bits, bigger x2APIC ids would need interrupt remapping.
+    pub unsafe fn route(&self, gsi: u32, apic_id: u32, vector: u8, mode: LineMode) -> Result<(), &'static str> {
+        if apic_id > 0xFF {
+            return Err("APIC id doesn't fit in an IOAPIC redirection entry");
+        }
+        let low_index = self.redirection_index(gsi);
+
+        // Mask the line while the entry is half written
+        let mut low = self.read(low_index) | REDIRECTION_MASKED;
+        self.write(low_index, low);
+
+        let mut high = self.read(low_index + 1);
+        high &= !0xFF00_0000;
+        high |= apic_id << 24;
+        self.write(low_index + 1, high);
+
+        // Physical destination, fixed delivery
+        low &= !(REDIRECTION_VECTOR | REDIRECTION_DELIVERY_MODE | REDIRECTION_LOGICAL);
+        low &= !(REDIRECTION_ACTIVE_LOW | REDIRECTION_LEVEL);
+        low |= vector as u32;
+        if mode.active_low {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if mode.level_triggered {
+            low |= REDIRECTION_LEVEL;
+        }
+        low &= !REDIRECTION_MASKED;
+        self.write(low_index, low);
+        Ok(())
+    }
 
-    let mut low = ioapic_read(ioapic_id, low_index);
+    pub unsafe fn set_masked(&self, gsi: u32, masked: bool) {
+        let low_index = self.redirection_index(gsi);
+        let mut low = self.read(low_index);
+        if masked {
+            low |= REDIRECTION_MASKED;
+        } else {
+            low &= !REDIRECTION_MASKED;
+        }
+        self.write(low_index, low);
+    }
 
-    // Unmask the IRQ
-    low &= !(1<<16);
+    unsafe fn mask_all(&self) {
+        for entry in 0..self.entry_count {
+            let low_index = IOAPIC_REG_REDIRECTION + entry * 2;
+            let low = self.read(low_index);
+            self.write(low_index, low | REDIRECTION_MASKED);
+        }
+    }
+}
 
-    // Set to physical delivery mode
-    low &= !(1<<11);
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// GSI routing
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Takes over the IOAPICs from the MADT and masks all of their lines.
+pub unsafe fn init(ioapics: Vec<acpi::platform::IoApic>) {
+    let mut list = IOAPICS.lock();
+    list.clear();
+    for info in ioapics.iter() {
+        let ioapic = IoApic::new(info);
+        ioapic.mask_all();
+        debug!(
+            "[IOAPIC] {} (hardware id {}) handles GSIs {}..{}",
+            ioapic.id, (ioapic.read(IOAPIC_REG_ID) >> 24) & 0xF,
+            ioapic.gsi_base, ioapic.gsi_base + ioapic.entry_count
+        );
+        list.push(ioapic);
+    }
+}
 
-    // Set to fixed delivery mode
-    low &= !0x700;
+/// Runs `f` with the IOAPIC `gsi` is connected to.
+fn with_ioapic<F, R>(gsi: u32, f: F) -> Result<R, &'static str>
+where
+    F: FnOnce(&IoApic) -> R,
+{
+    IOAPICS
+        .lock()
+        .iter()
+        .find(|ioapic| ioapic.handles(gsi))
+        .map(f)
+        .ok_or("No IOAPIC handles this GSI")
+}
 
-    // Set delivery vector
-    low &= !0xff;
-    low |= vector as u32;
+/// Sends `gsi` to `vector` on the local APIC `apic_id` and unmasks it.
+pub unsafe fn route(gsi: u32, apic_id: u32, vector: u8, mode: LineMode) -> Result<(), &'static str> {
+    with_ioapic(gsi, |ioapic| ioapic.route(gsi, apic_id, vector, mode))?
+}
 
-    ioapic_write(ioapic_id, low_index, low);
+pub unsafe fn set_masked(gsi: u32, masked: bool) -> Result<(), &'static str> {
+    with_ioapic(gsi, |ioapic| ioapic.set_masked(gsi, masked))
 }
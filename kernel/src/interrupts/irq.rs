@@ -0,0 +1,195 @@
+use alloc::vec::Vec;
+
+use acpi::platform::{InterruptSourceOverride, Polarity, TriggerMode};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use super::{apic, ioapic, InterruptContext, KernelGs};
+use super::ioapic::LineMode;
+
+/// First vector handed out by `register_irq`. Everything below is either an exception
+/// or one of the fixed `InterruptIndex` vectors.
+const DYNAMIC_VECTOR_START: usize = 0x50;
+const DYNAMIC_VECTOR_COUNT: usize = 32;
+
+/// Called when the IRQ fires. The EOI is sent after it returns, so it shouldn't take long.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Every dynamic vector is in use
+    NoFreeVector,
+    AlreadyRegistered,
+    NotRegistered,
+    /// None of the IOAPICs handles the GSI
+    NoIoApic,
+    /// The current CPU's APIC id is too big for an IOAPIC redirection entry
+    UnreachableCpu,
+}
+
+#[derive(Clone, Copy)]
+struct IrqSlot {
+    gsi: u32,
+    handler: IrqHandler,
+}
+
+/// How an ISA IRQ is wired, from the MADT interrupt source overrides.
+#[derive(Debug, Clone, Copy)]
+struct IsaOverride {
+    isa_source: u8,
+    gsi: u32,
+    mode: LineMode,
+}
+
+lazy_static! {
+    /// Indexed by vector - `DYNAMIC_VECTOR_START`.
+    static ref IRQ_SLOTS: spin::RwLock<[Option<IrqSlot>; DYNAMIC_VECTOR_COUNT]> =
+        spin::RwLock::new([None; DYNAMIC_VECTOR_COUNT]);
+    static ref ISA_OVERRIDES: spin::Mutex<Vec<IsaOverride>> = spin::Mutex::new(Vec::new());
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Interrupt source overrides
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Remembers how the ISA IRQs are wired. Called by `initialize_apic`.
+pub(super) fn set_interrupt_source_overrides(isos: &[InterruptSourceOverride]) {
+    let mut overrides = ISA_OVERRIDES.lock();
+    overrides.clear();
+    for iso in isos {
+        overrides.push(IsaOverride {
+            isa_source: iso.isa_source,
+            gsi: iso.global_system_interrupt,
+            // Whatever is left at `SameAsBus` keeps the ISA default
+            mode: LineMode {
+                active_low: match iso.polarity {
+                    Polarity::ActiveLow => true,
+                    _ => false,
+                },
+                level_triggered: match iso.trigger_mode {
+                    TriggerMode::Level => true,
+                    _ => false,
+                },
+            },
+        });
+    }
+}
+
+/// Returns the GSI an ISA IRQ is connected to.
+pub fn isa_irq_to_gsi(isa_irq: u8) -> u32 {
+    ISA_OVERRIDES
+        .lock()
+        .iter()
+        .find(|iso| iso.isa_source == isa_irq)
+        .map_or(isa_irq as u32, |iso| iso.gsi)
+}
+
+/// GSIs below this are identity mapped ISA IRQs unless an override says otherwise.
+const ISA_GSI_COUNT: u32 = 16;
+
+/// Returns the polarity and trigger mode of a GSI. Lines without an override are treated like
+/// plain ISA lines below GSI 16 and like PCI lines above, use `register_irq_with_mode` for
+/// anything else.
+pub fn gsi_mode(gsi: u32) -> LineMode {
+    let default = if gsi < ISA_GSI_COUNT { LineMode::ISA } else { LineMode::PCI };
+    ISA_OVERRIDES
+        .lock()
+        .iter()
+        .find(|iso| iso.gsi == gsi)
+        .map_or(default, |iso| iso.mode)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Registration
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Routes `gsi` to a free vector on the current CPU and calls `handler` whenever it fires.
+/// Returns the vector. The polarity and trigger mode come from `gsi_mode`.
+///
+/// Lines that one of the fixed `InterruptIndex` vectors is routed to shouldn't be registered here.
+pub fn register_irq(gsi: u32, handler: IrqHandler) -> Result<u8, IrqError> {
+    register_irq_with_mode(gsi, gsi_mode(gsi), handler)
+}
+
+/// Like `register_irq`, for lines whose polarity and trigger mode the caller knows better,
+/// like PCI interrupts routed through the ACPI tables.
+pub fn register_irq_with_mode(gsi: u32, mode: LineMode, handler: IrqHandler) -> Result<u8, IrqError> {
+    let apic_id = crate::smp::current_apic_id();
+    if apic_id > 0xFF {
+        return Err(IrqError::UnreachableCpu);
+    }
+    let vector = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slots = IRQ_SLOTS.write();
+        if slots.iter().flatten().any(|slot| slot.gsi == gsi) {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let index = slots.iter().position(Option::is_none).ok_or(IrqError::NoFreeVector)?;
+        slots[index] = Some(IrqSlot { gsi, handler });
+        Ok((DYNAMIC_VECTOR_START + index) as u8)
+    })?;
+
+    let routed = unsafe { ioapic::route(gsi, apic_id, vector, mode) };
+    if routed.is_err() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            IRQ_SLOTS.write()[vector as usize - DYNAMIC_VECTOR_START] = None;
+        });
+        return Err(IrqError::NoIoApic);
+    }
+    debug!("[IRQ] GSI {} is on vector {:#X}", gsi, vector);
+    Ok(vector)
+}
+
+/// Like `register_irq`, for an ISA IRQ number like 1 for the keyboard.
+pub fn register_isa_irq(isa_irq: u8, handler: IrqHandler) -> Result<u8, IrqError> {
+    register_irq(isa_irq_to_gsi(isa_irq), handler)
+}
+
+/// Masks `gsi` and frees its vector.
+pub fn unregister_irq(gsi: u32) -> Result<(), IrqError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slots = IRQ_SLOTS.write();
+        let slot = slots
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |slot| slot.gsi == gsi))
+            .ok_or(IrqError::NotRegistered)?;
+        unsafe { ioapic::set_masked(gsi, true).map_err(|_| IrqError::NoIoApic)?; }
+        *slot = None;
+        Ok(())
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Dispatch
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Installs the stubs of every dynamic vector. They all end up in `dispatch`.
+pub(super) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    for (index, &stub) in IRQ_STUBS.iter().enumerate() {
+        idt[DYNAMIC_VECTOR_START + index].set_handler_fn(stub);
+    }
+}
+
+fn dispatch(index: usize, stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
+    // Copied out so the handler runs without the lock held
+    let handler = IRQ_SLOTS.read()[index].map(|slot| slot.handler);
+    if let Some(handler) = handler {
+        handler();
+    }
+    //An unregistered vector can still fire once if the IRQ was already in flight
+    unsafe { apic::apic_send_eoi(); }
+}
+
+/// x86-interrupt handlers don't get the vector, so every dynamic vector gets its own stub.
+macro_rules! irq_stubs {
+    ($($index:literal),*) => {
+        static IRQ_STUBS: [HandlerFunc; DYNAMIC_VECTOR_COUNT] = [$({
+            extern "x86-interrupt" fn stub(stack_frame: &mut InterruptStackFrame) {
+                dispatch($index, stack_frame);
+            }
+            stub
+        }),*];
+    };
+}
+
+irq_stubs!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+);
@@ -10,7 +10,12 @@ use alloc::vec::Vec;
 use crate::{print, println, gdt, hlt_loop};
 
 pub mod apic;
+pub mod apic_timer;
 pub mod ioapic;
+pub mod irq;
+mod crash;
+
+use crash::{CrashFrame, CrashReport};
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // PIC
@@ -24,36 +29,31 @@ pub const PIC_OFFSET: u8 = 32;
 //     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 //NOTE: Maybe this belongs in apic.rs?
-pub fn initialize_apic(id: u8, isos: Vec<InterruptSourceOverride>) {
+/// Routes the default ISA IRQs to the local APIC `apic_id`.
+pub fn initialize_apic(apic_id: u32, isos: Vec<InterruptSourceOverride>) {
     unsafe {
         apic::disable_pic();
 
         crate::hardware::rtc::enable_rtc(6); //Default value of 1024 hz
 
-        apic::enable_apic(id);
-
-        let mut timer_irq    = 0;
-        let mut keyboard_irq = 1;
-        let mut spurious_irq = 7;
-        let mut rtc_irq      = 8;
-
-        for iso in isos.iter() {
-            match iso.isa_source {
-                0 => timer_irq = iso.global_system_interrupt,
-                1 => keyboard_irq = iso.global_system_interrupt,
-                7 => spurious_irq = iso.global_system_interrupt,
-                8 => rtc_irq = iso.global_system_interrupt,
-                _ => {}, //Do nothing
-            }
-        }
-
-        // Default IRQs
-        ioapic::ioapic_set_irq(timer_irq, id as u32, InterruptIndex::Timer.as_u8());
-        ioapic::ioapic_set_irq(keyboard_irq, id as u32, InterruptIndex::Keyboard.as_u8());
-        ioapic::ioapic_set_irq(spurious_irq, id as u32, InterruptIndex::Spurious.as_u8());
-        ioapic::ioapic_set_irq(rtc_irq, id as u32, InterruptIndex::RTC.as_u8());
+        apic::enable_apic();
+    }
 
-        //apic::apic_set_timer(id);
+    irq::set_interrupt_source_overrides(&isos);
+
+    // Default IRQs
+    let default_irqs = [
+        (0, InterruptIndex::Timer),
+        (1, InterruptIndex::Keyboard),
+        (7, InterruptIndex::Spurious),
+        (8, InterruptIndex::RTC),
+    ];
+    for &(isa_irq, index) in default_irqs.iter() {
+        let gsi = irq::isa_irq_to_gsi(isa_irq);
+        unsafe {
+            ioapic::route(gsi, apic_id, index.as_u8(), irq::gsi_mode(gsi))
+                .unwrap_or_else(|err| panic!("Failed to route ISA IRQ {}: {}", isa_irq, err));
+        }
     }
 }
 
@@ -63,7 +63,8 @@ pub enum InterruptIndex {
     Timer = PIC_OFFSET,
     Keyboard = PIC_OFFSET + 1,
 
-    HPET_Timer = PIC_OFFSET + 2,
+    HPET_OneShot = PIC_OFFSET + 3,
+    LapicTimer = PIC_OFFSET + 4,
 
     Spurious = PIC_OFFSET + 7,
     RTC = PIC_OFFSET + 8,
@@ -71,6 +72,13 @@ pub enum InterruptIndex {
 
     PrimaryATA = PIC_OFFSET + 14,
     SecondaryATA = PIC_OFFSET + 15,
+
+    // IPIs, kept away from the IRQ vectors
+    Reschedule = 0xF0,
+    TlbShootdown = 0xF1,
+
+    // Set in the spurious vector register of every local APIC
+    ApicSpurious = apic::SPURIOUS_VECTOR,
 }
 
 impl InterruptIndex {
@@ -92,17 +100,33 @@ lazy_static! {
 
         // Exceptions
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // The rest go through the stubs in `crash.s`, which save the registers for the report
         unsafe {
+            use crash::*;
+            idt.divide_error.set_handler_fn(stub(asm_divide_error_entry));
+            idt.debug.set_handler_fn(stub(asm_debug_entry));
+            idt.overflow.set_handler_fn(stub(asm_overflow_entry));
+            idt.bound_range_exceeded.set_handler_fn(stub(asm_bound_range_exceeded_entry));
+            idt.invalid_opcode.set_handler_fn(stub(asm_invalid_opcode_entry));
+            idt.device_not_available.set_handler_fn(stub(asm_device_not_available_entry));
+            idt.invalid_tss.set_handler_fn(stub(asm_invalid_tss_entry));
+            idt.segment_not_present.set_handler_fn(stub(asm_segment_not_present_entry));
+            idt.stack_segment_fault.set_handler_fn(stub(asm_stack_segment_fault_entry));
+            idt.general_protection_fault.set_handler_fn(stub(asm_general_protection_entry));
+            idt.page_fault.set_handler_fn(stub(asm_page_fault_entry));
+            idt.x87_floating_point.set_handler_fn(stub(asm_x87_floating_point_entry));
+            idt.alignment_check.set_handler_fn(stub(asm_alignment_check_entry));
+            idt.simd_floating_point.set_handler_fn(stub(asm_simd_floating_point_entry));
+            idt.non_maskable_interrupt
+                .set_handler_fn(stub(asm_nmi_entry))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(stub(asm_machine_check_entry))
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_fn(stub(asm_double_fault_entry))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
 
         // Legacy IRQ interrupts
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
@@ -112,7 +136,17 @@ lazy_static! {
         idt[InterruptIndex::ACPI.as_usize()].set_handler_fn(acpi_interrupt_handler);
 
         // Hardcoded interrupts
-        idt[InterruptIndex::HPET_Timer.as_usize()].set_handler_fn(hpet_interrupt_handler);
+        idt[InterruptIndex::HPET_OneShot.as_usize()].set_handler_fn(hpet_oneshot_interrupt_handler);
+        idt[InterruptIndex::LapicTimer.as_usize()].set_handler_fn(lapic_timer_interrupt_handler);
+
+        // IPIs
+        idt[InterruptIndex::Reschedule.as_usize()].set_handler_fn(reschedule_interrupt_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_interrupt_handler);
+
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_interrupt_handler);
+
+        // Vectors handed out by `irq::register_irq`
+        irq::install_stubs(&mut idt);
 
         idt
     };
@@ -128,67 +162,280 @@ pub fn init_idt() {
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 /// Breakpoint handler
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
     panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
     // unsafe { apic::send_apic_eoi(0); }
 }
 
+// The handlers below are called by the stubs in `crash.s`, so the reports include the general
+// purpose registers. None of them return.
+
+/// Debug exception, raised by hardware breakpoints and single stepping. Nothing sets those up
+/// in the kernel, and there's no debugger for user programs.
+#[no_mangle]
+extern "C" fn debug_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    let report = CrashReport::new("DEBUG", frame).with_detail("DR6", crash::dr6());
+    handle_fault(report);
+}
+
+/// Non-maskable interrupt. Other CPUs send these to stop this one when they panic,
+/// any other NMI means a hardware error. Runs on its own stack, see `KernelGs::enter_paranoid`.
+#[no_mangle]
+extern "C" fn nmi_handler(frame: &CrashFrame) -> ! {
+    use x86_64::instructions::port::Port;
+    let _gs = KernelGs::enter_paranoid();
+    if crate::smp::is_halting() {
+        crate::smp::halt();
+    }
+    //Bits 6 and 7 say whether the NMI came from a bus or memory error
+    let system_control: u8 = unsafe { Port::new(0x61).read() };
+    let report = CrashReport::new("NON-MASKABLE INTERRUPT", frame)
+        .with_detail("System Control Port B", system_control as u64);
+    panic!("{}", report);
+}
+
+/// Overflow, raised by `into`
+#[no_mangle]
+extern "C" fn overflow_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("OVERFLOW", frame));
+}
+
+/// Bound range exceeded, raised by `bound`
+#[no_mangle]
+extern "C" fn bound_range_exceeded_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("BOUND RANGE EXCEEDED", frame));
+}
+
+/// Invalid opcode
+#[no_mangle]
+extern "C" fn invalid_opcode_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("INVALID OPCODE", frame));
+}
+
+/// Device not available. The FPU state isn't switched lazily, so this only happens
+/// if the FPU or SSE isn't enabled.
+#[no_mangle]
+extern "C" fn device_not_available_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("DEVICE NOT AVAILABLE", frame));
+}
+
 /// Double fault handler
-extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+#[no_mangle]
+extern "C" fn double_fault_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    panic!("{}", CrashReport::new("DOUBLE FAULT", frame).with_error_code());
 }
 
 /// Page fault handler
-extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
+#[no_mangle]
+extern "C" fn page_fault_handler(frame: &CrashFrame) -> ! {
     use x86_64::registers::control::Cr2;
-
-    // println!("EXCEPTION: PAGE FAULT");
-    // println!("Accessed Address: {:?}", Cr2::read());
-    // println!("Error Code: {:?}", error_code);
-    // println!("{:#?}", stack_frame);
-    // hlt_loop();
-    panic!("EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\nRaw Error Code: 0b{:05b}\n{:#?}", Cr2::read(), error_code, error_code.bits(), stack_frame);
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    let address = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    if from_user_mode(&frame.stack_frame) {
+        kill_faulting_thread("PAGE FAULT", &frame.stack_frame, Some(frame.error_code), Some(address.as_u64()));
+    }
+    let report = CrashReport::new("PAGE FAULT", frame)
+        .with_error_code()
+        .with_detail("Accessed Address", address.as_u64());
+    panic!("{}\nFlags: {:?}", report, error_code);
 }
 
 /// Divide error
-extern "x86-interrupt" fn divide_error_handler(_stack_frame: &mut InterruptStackFrame) {
-    println!("Divide error!");
+#[no_mangle]
+extern "C" fn divide_error_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("DIVIDE ERROR", frame));
 }
 
 /// General Protection Fault
-extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    // println!("EXCEPTION: GENERAL PROTECTION FAULT");
-    // println!("Error Code: {:?}", error_code);
-    // println!("{:#?}", stack_frame);
-    // hlt_loop();
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {:?}\n{:#?}", error_code, stack_frame);
+#[no_mangle]
+extern "C" fn general_protection_fault_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("GENERAL PROTECTION FAULT", frame).with_error_code());
 }
 
 /// Stack segment fault
-extern "x86-interrupt" fn stack_segment_fault_handler(_stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    panic!("Stack segment fault!");
+#[no_mangle]
+extern "C" fn stack_segment_fault_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("STACK SEGMENT FAULT", frame).with_error_code());
 }
 
 /// Invalid TSS
-extern "x86-interrupt" fn invalid_tss_handler(_stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    panic!("Invalid TSS!");
+#[no_mangle]
+extern "C" fn invalid_tss_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("INVALID TSS", frame).with_error_code());
 }
 
 /// Segment not present
-extern "x86-interrupt" fn segment_not_present_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    panic!("EXCEPTION: SEGMENT NOT PRESENT\nError Code: {:?}\n{:?}", error_code, stack_frame);
+#[no_mangle]
+extern "C" fn segment_not_present_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("SEGMENT NOT PRESENT", frame).with_error_code());
+}
+
+/// x87 floating point error, only raised with CR0.NE set
+#[no_mangle]
+extern "C" fn x87_floating_point_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    let report = CrashReport::new("X87 FLOATING POINT", frame)
+        .with_detail("FPU Status Word", crash::x87_status_word());
+    handle_fault(report);
+}
+
+/// Alignment check, only raised in ring 3 with CR0.AM and RFLAGS.AC set
+#[no_mangle]
+extern "C" fn alignment_check_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    handle_fault(CrashReport::new("ALIGNMENT CHECK", frame).with_error_code());
+}
+
+/// Machine check. The hardware found an error it couldn't correct, so this always panics,
+/// even if it was user code that ran into it. Runs on its own stack like the NMI handler.
+#[no_mangle]
+extern "C" fn machine_check_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter_paranoid();
+    let report = CrashReport::new("MACHINE CHECK", frame)
+        .with_detail("MCG_STATUS", crash::mcg_status());
+    panic!("{}", report);
+}
+
+/// SIMD floating point exception
+#[no_mangle]
+extern "C" fn simd_floating_point_handler(frame: &CrashFrame) -> ! {
+    let _gs = KernelGs::enter(&frame.stack_frame);
+    let report = CrashReport::new("SIMD FLOATING POINT", frame)
+        .with_detail("MXCSR", crash::mxcsr());
+    handle_fault(report);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Fault handling
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Returns true if the interrupted code ran in ring 3.
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Kills the faulting thread if the exception came from user mode, panics with the report otherwise.
+/// Only for exceptions without an address, page faults have their own handling.
+fn handle_fault(report: CrashReport) -> ! {
+    if from_user_mode(report.stack_frame()) {
+        kill_faulting_thread(report.exception, report.stack_frame(), report.error_code, None);
+    }
+    panic!("{}", report);
+}
+
+/// Ends the thread that caused `exception` in user mode and marks its process as killed,
+/// so the other threads of the process follow once they enter the kernel.
+///
+/// Never returns to the faulting code. The GS base stays the kernel one, which is what the
+/// thread that gets switched to expects.
+fn kill_faulting_thread(
+    exception: &'static str,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<u64>,
+) -> ! {
+    use crate::multitasking::process::{ExitReason, UserFault};
+
+    let fault = UserFault {
+        exception,
+        instruction_pointer: stack_frame.instruction_pointer.as_u64(),
+        error_code,
+        address,
+    };
+    warn!(
+        "Thread {} killed by {} at {:#X} (error code {:#X?}, address {:#X?})",
+        crate::multitasking::current_thread_id().as_u64(), exception,
+        fault.instruction_pointer, fault.error_code, fault.address
+    );
+    crate::multitasking::exit_thread_with(ExitReason::Fault(fault));
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Interrupt context tracking
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Returns true while an IRQ handler is running on this CPU. Blocking is not allowed in there.
+pub fn in_interrupt_context() -> bool {
+    crate::smp::percpu::current().in_interrupt()
+}
+
+/// Marks the current code as running in interrupt context until dropped.
+/// Has to be dropped before a handler switches threads.
+struct InterruptContext;
+
+impl InterruptContext {
+    fn enter() -> Self {
+        crate::smp::percpu::current().enter_interrupt();
+        InterruptContext
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        crate::smp::percpu::current().leave_interrupt();
+    }
+}
+
+/// Switches to the kernel GS base if the interrupt came from ring 3, and back to the user one
+/// when dropped. Has to be the first thing in every handler and live until it returns, since
+/// everything in between may use the per-CPU data.
+struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = stack_frame.code_segment & 3 == 3;
+        if swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)); }
+        }
+        KernelGs { swapped }
+    }
+
+    /// For NMIs and machine checks, which can arrive in ring 0 before the `swapgs` of the
+    /// syscall entry or after the one on the way out. Checks the GS base itself instead.
+    fn enter_paranoid() -> Self {
+        let swapped = !crate::smp::percpu::kernel_gs_active();
+        if swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)); }
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)); }
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Legacy IRQ handlers
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 /// Timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
     // print!(".");
-    unsafe { apic::apic_send_eoi(0); }
+    unsafe { apic::apic_send_eoi(); }
 }
 
 /// Keyboard interrupt handler
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
     use x86_64::instructions::port::Port;
 
     debug!("Keyboard interrupt!");
@@ -197,41 +444,83 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
-    unsafe { apic::apic_send_eoi(0); }
+    unsafe { apic::apic_send_eoi(); }
 }
 
-extern "x86-interrupt" fn acpi_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+extern "x86-interrupt" fn acpi_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
     println!("ACPI INTERRUPT!");
 
-    unsafe { apic::apic_send_eoi(0); }
+    unsafe { apic::apic_send_eoi(); }
 }
 
-extern "x86-interrupt" fn hpet_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // println!("HPET INTERRUPT!");
-    // print!(";");
+extern "x86-interrupt" fn hpet_oneshot_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
+    crate::timer::handle_interrupt();
+    unsafe { apic::apic_send_eoi(); }
+}
+
+/// Scheduler tick, see `apic_timer::start_scheduler_tick`
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    {
+        let _irq = InterruptContext::enter();
+        //Also catches deadlines that passed while the HPET one-shot timer was being armed
+        crate::timer::handle_interrupt();
+        unsafe { apic::apic_send_eoi(); }
+    }
+    // Threads of a killed process that never make a syscall end here
+    if from_user_mode(stack_frame) && crate::multitasking::current_process_killed() {
+        crate::multitasking::exit_thread();
+    }
+    crate::multitasking::timer_tick();
+}
 
-    unsafe { apic::apic_send_eoi(0); }
-    crate::multitasking::yield_now();
+/// Sent by other CPUs when they queued work for this one, see `smp::send_reschedule_ipi`
+extern "x86-interrupt" fn reschedule_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    {
+        let _irq = InterruptContext::enter();
+        unsafe { apic::apic_send_eoi(); }
+    }
+    crate::multitasking::invoke_scheduler();
+}
+
+/// Sent by other CPUs when they changed mappings this one may have cached, see `tlb::shootdown`
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
+    crate::memory::tlb::handle_shootdown();
+    unsafe { apic::apic_send_eoi(); }
 }
 
-extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use core::sync::atomic::Ordering;
-    crate::hardware::rtc::TICK_COUNT.fetch_add(1, Ordering::SeqCst);
+extern "x86-interrupt" fn rtc_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
     // if crate::hardware::rtc::TICK_COUNT.load(Ordering::SeqCst) > 16384 {
     //     debug!("hi 16384");
     // }
-    unsafe {
-        apic::apic_send_eoi(0);
+    crate::hardware::rtc::handle_interrupt();
+    unsafe { apic::apic_send_eoi(); }
+}
 
-        use cpuio::{inb, outb};
-        outb(0x0C, 0x70);
-        inb(0x71);
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
+    // Only acknowledge it if the line really raised an interrupt,
+    // otherwise the EOI would retire whatever else is in service
+    if apic::is_in_service(InterruptIndex::Spurious.as_u8()) {
+        unsafe { apic::apic_send_eoi(); }
     }
 }
 
-extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    //TODO: Check ISR to make sure it's not a real interrupt
-    unsafe { apic::apic_send_eoi(0); }
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    let _irq = InterruptContext::enter();
+    // Nothing is in service, so no EOI
+    apic::record_spurious_interrupt();
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
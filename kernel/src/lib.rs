@@ -41,6 +41,9 @@ pub use interrupts::apic;
 pub mod hardware;
 pub mod acpi_controller;
 pub mod multitasking;
+pub mod smp;
+pub mod timer;
+pub mod time;
 pub mod userspace;
 pub mod custom_elfloader;
 
@@ -51,6 +54,8 @@ use core::panic::PanicInfo;
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    smp::halt_other_cpus();
     // crate::vga_buffer::kernel_panic(info);
     println!("{}", info);
     loop {}
@@ -62,18 +67,32 @@ pub fn hlt_loop() -> ! {
     }
 }
 
-/// Handles initialization of the kernel. For now, this only initializes the GDT and the interrupt IDT.
+/// Handles initialization of the kernel on the BSP. For now, this initializes the GDT, the per-CPU
+/// data, the interrupt IDT and the syscall entry. The APs do the same in `smp::ap_entry`.
 pub fn init() {
-    //Enable syscall extensions on x86_64
-    {
-        let mut efer = x86_64::registers::model_specific::Efer::read();
-        efer |= x86_64::registers::control::EferFlags::NO_EXECUTE_ENABLE;
-        efer |= x86_64::registers::control::EferFlags::SYSTEM_CALL_EXTENSIONS;
-        unsafe {
-            x86_64::registers::model_specific::Efer::write(efer);
-        }
-    }
+    enable_cpu_extensions();
 
-    gdt::init();
+    let tss = gdt::init();
+    unsafe { smp::percpu::init(0, smp::current_apic_id(), tss); }
+    memory::tlb::init();
+    memory::mmio::init();
     interrupts::init_idt();
+    gdt::setup_usermode_gdt();
+    userspace::syscall::init();
+}
+
+/// Enables syscall extensions, no-execute pages and SSE on x86_64. Has to run on every CPU.
+pub fn enable_cpu_extensions() {
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+    let mut efer = x86_64::registers::model_specific::Efer::read();
+    efer |= x86_64::registers::control::EferFlags::NO_EXECUTE_ENABLE;
+    efer |= x86_64::registers::control::EferFlags::SYSTEM_CALL_EXTENSIONS;
+    // SSE instructions raise #UD without OSFXSR, and SIMD exceptions turn into #UD without
+    // OSXMMEXCPT
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE;
+    unsafe {
+        x86_64::registers::model_specific::Efer::write(efer);
+        Cr4::write(cr4);
+    }
 }
@@ -10,7 +10,6 @@
 #[macro_use] extern crate alloc;
 
 use core::panic::PanicInfo;
-use core::sync::atomic::Ordering;
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 
 use bootloader::{BootInfo, entry_point};
@@ -34,7 +33,7 @@ use kernel::{
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use kernel::memory::BootInfoFrameAllocator;
+    use kernel::memory::BitmapFrameAllocator;
     use kernel::vga_buffer::ModeEnum;
     use vga::writers::{Text80x25, Graphics320x200x256, Graphics640x480x16, GraphicsWriter};
     use vga::colors::Color16;
@@ -65,12 +64,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     kernel_logger::init().expect("Failed to load the kernel logger!");
     println!("Hello, world!");
 
-    let regions = boot_info.memory_map.iter();
-    let addr_ranges = regions.map(|r| r.range.start_addr()..r.range.end_addr());
-    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-    let available_memory = frame_addresses.count() * 4;
-    println!("Memory available: {} KiB", available_memory);
-
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     kernel::memory::update_physical_memory_offset(phys_mem_offset.as_u64());
     {
@@ -78,15 +71,17 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         *mapper = unsafe { Some(kernel::memory::init(phys_mem_offset)) };
         let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
         *frame_allocator = unsafe {
-            Some(BootInfoFrameAllocator::init(&boot_info.memory_map))
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
         };
         println!("Mapper and frame allocator created!");
+        println!("Memory available: {} KiB", frame_allocator.as_ref().unwrap().free_frames() * 4);
     }
 
     {
         let mut mapper = kernel::memory::MAPPER.lock();
         let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
         kernel::allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).expect("Heap initialization failed!");
+        kernel::memory::init_kernel_p4_entries(frame_allocator.as_mut().unwrap());
     }
 
     // panic!("Test panic");
@@ -123,19 +118,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     debug!("Found ACPI data!");
 
-    {
-        let mut ioapics = kernel::interrupts::ioapic::IOAPICS.lock();
-        *ioapics = acpi_controller.get_io_apic();
-    }
+    unsafe { kernel::interrupts::apic::init_lapic(acpi_controller.get_apic_addr()); }
+    unsafe { kernel::interrupts::ioapic::init(acpi_controller.get_io_apic()); }
 
     x86_64::instructions::interrupts::without_interrupts(|| {
-        kernel::interrupts::initialize_apic(0, acpi_controller.get_io_apic_iso());
+        kernel::interrupts::initialize_apic(kernel::smp::current_apic_id(), acpi_controller.get_io_apic_iso());
     });
 
     let hpet_info = acpi_controller.get_hpet_info();
     // trace!("HPET_ADDR: {:#08X}", hpet_info.base_address);
-    kernel::hardware::hpet::HPET_BASE_ADDR.store(hpet_info.base_address as u64, Ordering::Relaxed);
-    kernel::hardware::hpet::initialize_hpet();
+    kernel::hardware::hpet::initialize_hpet(hpet_info.base_address as u64);
+
+    //Scheduler tick, calibrated against the HPET
+    unsafe { kernel::interrupts::apic_timer::start_scheduler_tick(); }
+    kernel::hardware::rtc::set_century_register(acpi_controller.get_century_register());
+    kernel::time::init();
 
     // debug!("[RTC] Sleeping for 2 seconds");
     // debug!("RDTSC value: {}", kernel::hardware::rdtsc::read_rdtsc());
@@ -158,6 +155,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         with_scheduler(|s| s.add_new_thread(test_thread));
     }
 
+    //Start the other cores, they pick up threads from the scheduler right away
+    kernel::smp::init(acpi_controller.get_ap_apic_ids());
+
     // Testing loading code at runtime. Pagefaults right now lol
     /*{
         let userspace_addr = 0xFF00_0000;
@@ -180,8 +180,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         with_scheduler(|s| s.add_new_thread(user_thread));
     }*/
 
-    //Userspace
-    // kernel::userspace::init();
     thread_entry();
 
     hlt_loop();
@@ -189,6 +187,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 fn idle_thread() -> ! {
     loop {
+        multitasking::reap();
         x86_64::instructions::hlt();
         multitasking::yield_now();
     }
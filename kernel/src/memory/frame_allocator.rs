@@ -0,0 +1,300 @@
+use x86_64::{
+    structures::paging::{
+        PhysFrame,
+        Size4KiB,
+        FrameAllocator,
+        FrameDeallocator,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Frames in the first MiB of physical memory.
+const LOW_MEMORY_FRAMES: usize = 256;
+
+/// A frame allocator that keeps one bit per physical frame, set when the frame is in use.
+///
+/// The bitmap covers every frame up to the end of the highest usable region and is stored in
+/// the first usable region above 1 MiB that's big enough to hold it.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    next: usize, //Word to start searching from, everything before it is likely in use
+}
+
+impl BitmapFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are
+    /// marked as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .expect("Memory map has no usable regions!");
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_frames = ((word_count * 8) as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_start = bitmap_location(memory_map, bitmap_frames * FRAME_SIZE)
+            .expect("No usable region is big enough to hold the frame bitmap!");
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+
+        // Everything is in use, except for the usable regions
+        for word in allocator.bitmap.iter_mut() {
+            *word = !0;
+        }
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear(index);
+                allocator.usable_frames += 1;
+            }
+        }
+        allocator.free_frames = allocator.usable_frames;
+
+        // The bitmap itself and low memory are never handed out.
+        // The SMP trampoline gets copied below 1 MiB, see `smp::TRAMPOLINE_BASE`
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.mark_used(index);
+        }
+        for index in 0..core::cmp::min(LOW_MEMORY_FRAMES, frame_count) {
+            if !allocator.is_used(index) {
+                allocator.mark_used(index);
+            }
+        }
+
+        allocator
+    }
+
+    /// Number of frames that are still available.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of usable frames that are currently handed out, including the bitmap itself.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Number of frames marked usable in the memory map.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Allocates `count` physically contiguous frames, starting at a frame number that is a
+    /// multiple of `align` (in frames, has to be a power of two). Meant for DMA buffers.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment has to be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let mut start = 0;
+        'search: while start + count <= self.frame_count {
+            for index in start..start + count {
+                if self.is_used(index) {
+                    start = align_up(index + 1, align);
+                    continue 'search;
+                }
+            }
+            for index in start..start + count {
+                self.mark_used(index);
+            }
+            return Some(frame_at(start));
+        }
+        None
+    }
+
+    /// Frees `count` frames starting at `start`, as returned by `allocate_contiguous`.
+    ///
+    /// Unsafe because the frames must not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = frame_index(start);
+        for index in first..first + count {
+            self.mark_free(index);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index), "frame {:#X} is already in use", index);
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        assert!(self.is_used(index), "double free of frame {:#X}", index);
+        self.clear(index);
+        self.free_frames += 1;
+        self.next = core::cmp::min(self.next, index / BITS_PER_WORD);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        for word_index in self.next..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if index >= self.frame_count {
+                break;
+            }
+            self.next = word_index;
+            self.mark_used(index);
+            return Some(frame_at(index));
+        }
+        self.next = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.mark_free(frame_index(frame));
+    }
+}
+
+/// Start of the first usable memory above 1 MiB with room for `size` bytes.
+/// Regions that cross 1 MiB only count from there on, low memory is kept for the SMP trampoline.
+fn bitmap_location(memory_map: &MemoryMap, size: u64) -> Option<u64> {
+    let low_memory_end = LOW_MEMORY_FRAMES as u64 * FRAME_SIZE;
+    memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| (core::cmp::max(r.range.start_addr(), low_memory_end), r.range.end_addr()))
+        .find(|&(start, end)| end.saturating_sub(start) >= size)
+        .map(|(start, _)| start)
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// An allocator over `frame_count` free frames, with a bitmap that doesn't come from a memory map.
+#[cfg(test)]
+fn test_allocator(bitmap: &'static mut [u64], frame_count: usize) -> BitmapFrameAllocator {
+    for word in bitmap.iter_mut() {
+        *word = !0;
+    }
+    let mut allocator = BitmapFrameAllocator {
+        bitmap,
+        frame_count,
+        usable_frames: frame_count,
+        free_frames: 0,
+        next: 0,
+    };
+    for index in 0..frame_count {
+        allocator.clear(index);
+    }
+    allocator.free_frames = frame_count;
+    allocator
+}
+
+#[test_case]
+fn test_bitmap_allocate_and_free() {
+    static mut BITMAP: [u64; 2] = [0; 2];
+    let mut allocator = test_allocator(unsafe { &mut BITMAP }, 100);
+
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_eq!(frame_index(first), 0);
+    assert_eq!(frame_index(second), 1);
+    assert_eq!(allocator.free_frames(), 98);
+
+    // Freed frames get handed out again before higher ones
+    unsafe { allocator.deallocate_frame(first); }
+    assert_eq!(allocator.free_frames(), 99);
+    assert_eq!(allocator.allocate_frame(), Some(first));
+
+    // Frames past `frame_count` in the last word are never handed out
+    while allocator.allocate_frame().is_some() {}
+    assert_eq!(allocator.free_frames(), 0);
+    assert_eq!(allocator.used_frames(), 100);
+}
+
+#[test_case]
+fn test_bitmap_allocate_contiguous() {
+    static mut BITMAP: [u64; 2] = [0; 2];
+    let mut allocator = test_allocator(unsafe { &mut BITMAP }, 128);
+
+    allocator.mark_used(2);
+    allocator.mark_used(5);
+    // The aligned runs at 0 and 4 both contain a used frame, so the first fit starts at 8
+    let start = allocator.allocate_contiguous(4, 4).unwrap();
+    assert_eq!(frame_index(start), 8);
+    for index in 8..12 {
+        assert!(allocator.is_used(index));
+    }
+    assert_eq!(allocator.free_frames(), 128 - 2 - 4);
+
+    unsafe { allocator.deallocate_contiguous(start, 4); }
+    assert_eq!(allocator.free_frames(), 126);
+    assert!(allocator.allocate_contiguous(200, 1).is_none());
+}
+
+#[test_case]
+fn test_bitmap_skips_low_memory() {
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+    let usable = |start, end| MemoryRegion {
+        range: FrameRange::new(start, end),
+        region_type: MemoryRegionType::Usable,
+    };
+
+    // The low region is big enough, but the trampoline goes there
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(usable(0x1000, 0x9F000));
+    memory_map.add_region(usable(0x100000, 0x102000));
+    memory_map.add_region(usable(0x200000, 0x800000));
+    assert_eq!(bitmap_location(&memory_map, 0x4000), Some(0x200000));
+
+    // A region that crosses 1 MiB is only used from there on
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(usable(0x1000, 0x110000));
+    assert_eq!(bitmap_location(&memory_map, 0x4000), Some(0x100000));
+    assert_eq!(bitmap_location(&memory_map, 0x20000), None);
+}
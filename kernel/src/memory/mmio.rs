@@ -0,0 +1,158 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{mapper, Mapper, Page, PageTableFlags as Flags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::MMIO_REGION_START;
+
+const IA32_PAT: u32 = 0x277;
+
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED_MINUS: u64 = 0x07; //UC, but MTRRs can turn it into WC
+
+/// The power-on PAT, except that entry 1 (PWT) is write-combining instead of write-through.
+/// Entry 3 (PCD | PWT) stays strong uncacheable. Nothing uses the entries with the PAT bit set.
+const PAT_VALUE: u64 = PAT_WRITE_BACK
+    | PAT_WRITE_COMBINING << 8
+    | PAT_UNCACHED_MINUS << 16
+    | PAT_UNCACHEABLE << 24
+    | PAT_WRITE_BACK << 32
+    | PAT_WRITE_THROUGH << 40
+    | PAT_UNCACHED_MINUS << 48
+    | PAT_UNCACHEABLE << 56;
+
+/// Next free address in the MMIO region. Mappings are never taken down, so a bump allocator does.
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_REGION_START);
+
+/// Programs the PAT so `CacheMode::WriteCombining` works. Has to run on every CPU,
+/// all of them need the same PAT. Without a PAT write-combining mappings end up write-through.
+pub fn init() {
+    let has_pat = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |features| features.has_pat());
+    if !has_pat {
+        return;
+    }
+    unsafe {
+        // Nothing may be cached with the old attributes, see the Intel SDM 11.12.4
+        asm!("wbinvd", options(nomem, nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        asm!("wbinvd", options(nomem, nostack, preserves_flags));
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// Memory type of a MMIO mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device in program order. Right for registers.
+    Uncached,
+    /// Writes may be combined and reordered. Only for things like framebuffers.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn page_flags(self) -> Flags {
+        match self {
+            CacheMode::Uncached => Flags::NO_CACHE | Flags::WRITE_THROUGH, //PAT entry 3
+            CacheMode::WriteCombining => Flags::WRITE_THROUGH,              //PAT entry 1
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// MMIO regions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Device memory mapped into the kernel half with the right memory type.
+/// Offsets passed to the accessors are relative to the physical address the region was mapped for.
+#[derive(Debug)]
+pub struct Mmio {
+    base: VirtAddr,
+    phys_addr: PhysAddr,
+    size: u64,
+}
+
+impl Mmio {
+    /// Maps `size` bytes of device memory starting at `phys_addr`, which doesn't have to be
+    /// page aligned. The mapping lives until the kernel stops.
+    ///
+    /// Unsafe because the range must not be RAM in use. Locks `MAPPER` and `FRAME_ALLOCATOR`,
+    /// so don't call it while holding either of them.
+    pub unsafe fn map(phys_addr: PhysAddr, size: u64, mode: CacheMode) -> Result<Self, mapper::MapToError<Size4KiB>> {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+        let last_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr + size.max(1) - 1u64);
+        let page_count = last_frame.start_address().as_u64() / Page::<Size4KiB>::SIZE
+            - first_frame.start_address().as_u64() / Page::<Size4KiB>::SIZE
+            + 1;
+        let start = MMIO_NEXT.fetch_add(page_count * Page::<Size4KiB>::SIZE, Ordering::SeqCst);
+        let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE | Flags::GLOBAL | mode.page_flags();
+        let mut mapper = super::MAPPER.lock();
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        let mapper = mapper.as_mut().expect("Mapper isn't initialized!");
+        let frame_allocator = frame_allocator.as_mut().expect("Frame allocator isn't initialized!");
+        let mut flush_batch = super::tlb::FlushBatch::new();
+        for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+            let page = first_page + i as u64;
+            flush_batch.add(page, mapper.map_to(page, frame, flags, frame_allocator)?);
+        }
+        // Fresh addresses, no other CPU can have them cached
+        flush_batch.flush_local();
+
+        Ok(Mmio {
+            base: first_page.start_address() + (phys_addr.as_u64() - first_frame.start_address().as_u64()),
+            phys_addr,
+            size,
+        })
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn register<T>(&self, offset: u64) -> *mut T {
+        let width = core::mem::size_of::<T>() as u64;
+        assert!(offset + width <= self.size, "MMIO access at {:#X} is out of bounds", offset);
+        assert!(offset % width == 0, "MMIO access at {:#X} is misaligned", offset);
+        (self.base + offset).as_mut_ptr()
+    }
+
+    /// Reads the register at `offset` with a single access of the width of `T`.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { core::ptr::read_volatile(self.register(offset)) }
+    }
+
+    /// Writes the register at `offset` with a single access of the width of `T`.
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { core::ptr::write_volatile(self.register(offset), value) }
+    }
+
+    pub fn read_u32(&self, offset: u64) -> u32 {
+        self.read(offset)
+    }
+
+    pub fn write_u32(&self, offset: u64, value: u32) {
+        self.write(offset, value)
+    }
+
+    pub fn read_u64(&self, offset: u64) -> u64 {
+        self.read(offset)
+    }
+
+    pub fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, value)
+    }
+}
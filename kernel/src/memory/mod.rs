@@ -0,0 +1,572 @@
+use x86_64::{
+    structures::paging::{
+        mapper,
+        PageTable,
+        PageTableEntry,
+        OffsetPageTable,
+        Page,
+        PhysFrame,
+        Mapper,
+        MapperAllSizes,
+        Size4KiB,
+        FrameAllocator,
+        FrameDeallocator,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    KERNEL_P4_ADDR.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Stack allocation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    pub fn size_in_pages(&self) -> u64 {
+        (self.end - self.start) / Page::<Size4KiB>::SIZE
+    }
+
+    /// Switches to the stack described by the StackBounds.
+    /// Unsafe because this can easily lead to memory unsafety and UB.
+    /// Do not access local variables after calling this!
+    pub unsafe fn switch_to(&self) {
+        asm!("
+            mov rsp, {0}
+            mov rbp, {0}",
+        in(reg) self.end.as_u64());
+    }
+}
+
+lazy_static! {
+    /// Virtual ranges of freed kernel stacks, reused by `alloc_stack` before taking new addresses.
+    static ref FREE_STACKS: spin::Mutex<Vec<StackBounds>> = spin::Mutex::new(Vec::new());
+}
+
+/// Allocates a stack for kernel threads
+pub fn alloc_stack(
+    size_in_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<StackBounds, mapper::MapToError<Size4KiB>> {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    static STACK_ALLOC_NEXT: AtomicU64 = AtomicU64::new(0x_5555_5555_0000);
+
+    let recycled = {
+        let mut free_stacks = FREE_STACKS.lock();
+        free_stacks
+            .iter()
+            .position(|bounds| bounds.size_in_pages() == size_in_pages)
+            .map(|index| free_stacks.swap_remove(index))
+    };
+
+    let (stack_start, stack_end) = match recycled {
+        Some(bounds) => (
+            Page::containing_address(bounds.start()),
+            Page::containing_address(bounds.end()),
+        ),
+        None => {
+            let guard_page_start = STACK_ALLOC_NEXT.fetch_add(
+                (size_in_pages + 1) * Page::<Size4KiB>::SIZE,
+                Ordering::SeqCst,
+            );
+            let guard_page = Page::from_start_address(VirtAddr::new(guard_page_start))
+                .expect("`STACK_ALLOC_NEXT` not page aligned");
+            (guard_page + 1, guard_page + 1 + size_in_pages)
+        }
+    };
+
+    // Global, kernel stacks look the same in every address space
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::GLOBAL;
+    let mut flush_batch = tlb::FlushBatch::new();
+    for page in Page::range(stack_start, stack_end) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(mapper::MapToError::FrameAllocationFailed)?;
+        unsafe { flush_batch.add(page, mapper.map_to(page, frame, flags, frame_allocator)?); }
+    }
+    // Only new mappings, which other CPUs can't have cached
+    flush_batch.flush_local();
+    Ok(StackBounds {
+        start: stack_start.start_address(),
+        end: stack_end.start_address(),
+    })
+}
+
+/// Unmaps a stack allocated by `alloc_stack`, returns its frames and puts the virtual range
+/// up for reuse.
+///
+/// Unsafe because nothing may run on, or point into, the stack anymore.
+pub unsafe fn free_stack(
+    bounds: StackBounds,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), mapper::UnmapError> {
+    let start_page = Page::containing_address(bounds.start());
+    let end_page = Page::containing_address(bounds.end());
+    let mut frames = Vec::new();
+    let mut flush_batch = tlb::FlushBatch::new();
+    for page in Page::range(start_page, end_page) {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush_batch.add(page, flush);
+        frames.push(frame);
+    }
+    // The thread may have run on any CPU, the frames can't be reused before all of them forgot it
+    flush_batch.shootdown_kernel();
+    for frame in frames {
+        frame_allocator.deallocate_frame(frame);
+    }
+    FREE_STACKS.lock().push(bounds);
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Memory mapping
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl MemoryBounds {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Address spaces
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Userspace memory lives in its own level 4 slots, which are never shared between address spaces.
+/// Everything outside of this range belongs to the kernel and is shared by all address spaces.
+pub const USER_SPACE_START: u64 = 0x_0000_2000_0000_0000;
+pub const USER_SPACE_END:   u64 = 0x_0000_4000_0000_0000;
+
+const USER_P4_START_INDEX: usize = (USER_SPACE_START >> 39) as usize;
+const USER_P4_END_INDEX:   usize = (USER_SPACE_END >> 39) as usize;
+
+/// Where ELF files get loaded and where user stacks are allocated from.
+pub const USER_CODE_START:  u64 = USER_SPACE_START;
+pub const USER_STACK_START: u64 = 0x_0000_3FFF_0000_0000;
+
+/// Kernel regions that get mapped after boot. Their level 4 entries are created up front,
+/// so address spaces created before the first mapping still see it.
+const KERNEL_DYNAMIC_REGIONS: &[u64] = &[
+    0x_5555_5555_0000, //Kernel stacks, see `alloc_stack`
+    MMIO_REGION_START, //Device registers, see `mmio::Mmio`
+];
+
+const MMIO_REGION_START: u64 = 0x_5600_0000_0000;
+
+static KERNEL_P4_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the level 4 table the kernel booted with. Kernel threads run on this table.
+pub fn kernel_p4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_P4_ADDR.load(Ordering::Relaxed)))
+}
+
+/// Creates the level 4 entries of every region in `KERNEL_DYNAMIC_REGIONS`.
+/// Has to be called before the first address space is created.
+pub fn init_kernel_p4_entries(frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let kernel_p4 = unsafe { table_at(kernel_p4_frame()) };
+    for &addr in KERNEL_DYNAMIC_REGIONS {
+        let entry = &mut kernel_p4[VirtAddr::new(addr).p4_index()];
+        if entry.is_unused() {
+            let frame = frame_allocator.allocate_frame().expect("Failed to allocate kernel level 3 table!");
+            unsafe { table_at(frame).zero(); }
+            entry.set_frame(frame, Flags::PRESENT | Flags::WRITABLE);
+        }
+    }
+}
+
+/// Returns the page table stored in `frame`, through the physical memory mapping.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + frame.start_address().as_u64();
+    &mut *(virt as *mut PageTable)
+}
+
+/// A set of page tables with its own level 4 table. The kernel half is shared with
+/// the boot page table, the user half is private.
+///
+/// Address spaces are not freed on drop, because that requires the frame allocator.
+/// Use `destroy` once nothing runs on it anymore.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    pcid: Option<u16>,
+    cpus: Arc<tlb::CpuTracker>,
+    next_stack: u64,
+}
+
+impl AddressSpace {
+    pub fn new(
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Self, mapper::MapToError<Size4KiB>> {
+        let p4_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(mapper::MapToError::FrameAllocationFailed)?;
+
+        let p4 = unsafe { table_at(p4_frame) };
+        let kernel_p4 = unsafe { table_at(kernel_p4_frame()) };
+        for (i, entry) in p4.iter_mut().enumerate() {
+            if i >= USER_P4_START_INDEX && i < USER_P4_END_INDEX {
+                entry.set_unused();
+            } else {
+                *entry = kernel_p4[i].clone();
+            }
+        }
+
+        Ok(AddressSpace {
+            p4_frame,
+            pcid: tlb::alloc_pcid(),
+            cpus: Arc::new(tlb::CpuTracker::new()),
+            next_stack: USER_STACK_START,
+        })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    /// What CR3 gets loaded with to switch to this address space.
+    pub fn root(&self) -> tlb::PageTableRoot {
+        tlb::PageTableRoot::new(self.p4_frame, self.pcid)
+    }
+
+    /// The CPUs that switched to this address space. A CPU has to mark itself before it
+    /// switches, so it gets included in shootdowns from now on. Shared, so the scheduler can
+    /// do that without looking up the process.
+    pub fn active_cpus(&self) -> Arc<tlb::CpuTracker> {
+        self.cpus.clone()
+    }
+
+    pub fn is_active(&self) -> bool {
+        use x86_64::registers::control::Cr3;
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// Returns a mapper for this address space.
+    /// Unsafe because the caller must not create a second mapper for the same address space.
+    pub unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let phys_mem_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        OffsetPageTable::new(table_at(self.p4_frame), phys_mem_offset)
+    }
+
+    /// Maps `size_in_pages` pages of user memory starting at the page containing `addr`.
+    /// Pages that are already mapped are left alone, so ELF segments can share a page.
+    pub fn map_user_memory(
+        &mut self,
+        addr: u64,
+        size_in_pages: u64,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<MemoryBounds, mapper::MapToError<Size4KiB>> {
+        use x86_64::structures::paging::PageTableFlags as Flags;
+
+        assert!(addr >= USER_SPACE_START && addr < USER_SPACE_END, "address outside of user space");
+
+        let start_page = Page::containing_address(VirtAddr::new(addr));
+        let end_page = start_page + size_in_pages;
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let mut flush_batch = tlb::FlushBatch::new();
+        for page in Page::range(start_page, end_page) {
+            if mapper.translate_page(page).is_ok() {
+                continue;
+            }
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(mapper::MapToError::FrameAllocationFailed)?;
+            unsafe { flush_batch.add(page, mapper.map_to(page, frame, flags, frame_allocator)?); }
+        }
+        // Pages that weren't mapped can't be cached, other CPUs don't need to know
+        if active {
+            flush_batch.flush_local();
+        }
+        Ok(MemoryBounds {
+            start: start_page.start_address(),
+            end: end_page.start_address(),
+        })
+    }
+
+    /// Allocates a stack for a user thread in this address space.
+    pub fn alloc_user_stack(
+        &mut self,
+        size_in_pages: u64,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<StackBounds, mapper::MapToError<Size4KiB>> {
+        let guard_page_start = self.next_stack;
+        self.next_stack += (size_in_pages + 1) * Page::<Size4KiB>::SIZE;
+
+        let stack_start = guard_page_start + Page::<Size4KiB>::SIZE;
+        let bounds = self.map_user_memory(stack_start, size_in_pages, frame_allocator)?;
+        Ok(StackBounds {
+            start: bounds.start(),
+            end: bounds.end(),
+        })
+    }
+
+    /// Copies `bytes` to `addr` in this address space. This goes through the physical memory
+    /// mapping, so the address space doesn't have to be active.
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+        let mut written = 0;
+        self.for_each_chunk(addr, bytes.len(), |dst, len| unsafe {
+            core::ptr::copy_nonoverlapping(bytes[written..].as_ptr(), dst, len);
+            written += len;
+        })
+    }
+
+    /// Zeroes `len` bytes at `addr` in this address space.
+    pub fn zero(&mut self, addr: u64, len: usize) -> Result<(), &'static str> {
+        self.for_each_chunk(addr, len, |dst, len| unsafe {
+            core::ptr::write_bytes(dst, 0, len);
+        })
+    }
+
+    /// Splits `addr..addr + len` at page boundaries and calls `f` with the physical memory
+    /// mapping of every piece.
+    fn for_each_chunk<F>(&mut self, addr: u64, len: usize, mut f: F) -> Result<(), &'static str>
+    where
+        F: FnMut(*mut u8, usize),
+    {
+        let phys_mem_offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+        let mapper = unsafe { self.mapper() };
+        let mut done = 0;
+        while done < len {
+            let virt = VirtAddr::new(addr + done as u64);
+            let phys = mapper.translate_addr(virt).ok_or("address is not mapped")?;
+            let page_left = 4096 - (virt.as_u64() % 4096) as usize;
+            let chunk = core::cmp::min(len - done, page_left);
+            f((phys_mem_offset + phys.as_u64()) as *mut u8, chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Frees every frame mapped in the user half, the page tables themselves and
+    /// the level 4 table.
+    /// Unsafe because nothing may run on, or still hold references into, this address space.
+    pub unsafe fn destroy(self, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+        assert!(!self.is_active(), "can't destroy the active address space");
+
+        // With PCIDs the entries stay cached after a switch, they have to go before the frames do.
+        // Without its own PCID the address space used the kernel one, and switching back to the
+        // kernel table doesn't flush that
+        let pcid = self.pcid.unwrap_or(tlb::KERNEL_PCID);
+        tlb::shootdown(tlb::Invalidate::AddressSpace(pcid), self.cpus.cpus());
+        if let Some(pcid) = self.pcid {
+            tlb::free_pcid(pcid);
+        }
+
+        let p4 = table_at(self.p4_frame);
+        for i in USER_P4_START_INDEX..USER_P4_END_INDEX {
+            free_entry(&mut p4[i], 3, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(self.p4_frame);
+    }
+}
+
+/// Frees whatever `entry` points to. `level` is the level of the table the entry points to,
+/// with 0 meaning the entry maps a regular 4KiB page.
+unsafe fn free_entry(
+    entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return, //Not present, and huge pages are never used in user space
+    };
+    if level > 0 {
+        for child in table_at(frame).iter_mut() {
+            free_entry(child, level - 1, frame_allocator);
+        }
+    }
+    entry.set_unused();
+    frame_allocator.deallocate_frame(frame);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+//Page allocation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+pub mod frame_allocator;
+pub use frame_allocator::BitmapFrameAllocator;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// TLB management
+///////////////////////////////////////////////////////////////////////////////////////////////////
+pub mod tlb;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// MMIO
+///////////////////////////////////////////////////////////////////////////////////////////////////
+pub mod mmio;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Address translation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    translate_addr_inner(addr, physical_memory_offset)
+}
+
+/// Private function that is called by `translate_addr`.
+///
+/// This function is safe to limit the scope of `unsafe` because Rust treats
+/// the whole body of unsafe functions as an unsafe block. This function must
+/// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+
+    // traverse the multi-level page table
+    for &index in &table_indexes {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Returns the flags that apply to `addr` in the active page table, or `None` if it isn't mapped.
+/// A page is only writable or user accessible if every level allows it, so those two flags are
+/// combined over the whole walk, the other ones come from the last entry.
+///
+/// Unsafe for the same reason as `translate_addr`.
+pub unsafe fn effective_page_flags(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<x86_64::structures::paging::PageTableFlags>
+{
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+    let mut combined = Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let mut flags = Flags::empty();
+
+    for &index in &table_indexes {
+        let table = &*(physical_memory_offset + frame.start_address().as_u64()).as_ptr::<PageTable>();
+        let entry = &table[index];
+        flags = entry.flags();
+        if !flags.contains(Flags::PRESENT) {
+            return None;
+        }
+        combined &= flags;
+        if flags.contains(Flags::HUGE_PAGE) {
+            break;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+
+    let restricted = Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    Some((flags - restricted) | (combined & restricted))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Singleton mapper, frame allocator and physical memory offset
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+lazy_static! {
+    pub static ref MAPPER: spin::Mutex<Option<OffsetPageTable<'static>>> = spin::Mutex::new(None);
+}
+
+lazy_static! {
+    pub static ref FRAME_ALLOCATOR: spin::Mutex<Option<BitmapFrameAllocator>> = spin::Mutex::new(None);
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+pub static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+pub fn update_physical_memory_offset(phys_mem_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(phys_mem_offset, Ordering::Relaxed);
+}
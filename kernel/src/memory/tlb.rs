@@ -0,0 +1,367 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use raw_cpuid::CpuId;
+use x86_64::structures::paging::{mapper::MapperFlush, Page, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::interrupts::apic::{self, IpiDestination, IpiKind};
+use crate::interrupts::InterruptIndex;
+use crate::multitasking::thread::CpuMask;
+use crate::smp::{self, MAX_CPUS};
+
+/// Ranges bigger than this are invalidated with a full flush instead of page by page.
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+const CR4_PGE: u64 = 1 << 7;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR3_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const CR3_PCID_MASK: u64 = 0xFFF;
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+const INVPCID_ALL_CONTEXTS: u64 = 2; //Including global pages
+
+/// PCID 0 belongs to the kernel page table, and to address spaces that didn't get one.
+pub const KERNEL_PCID: u16 = 0;
+const MAX_PCID: u16 = 4095;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Initialization
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Turns on global pages, and PCIDs if the CPU has both PCID and INVPCID. INVPCID is needed to
+/// drop the entries of address spaces that aren't active. Has to run on every CPU, the BSP
+/// decides for all of them.
+pub fn init() {
+    let supported = if smp::cpu_index() == 0 {
+        let cpuid = CpuId::new();
+        let pcid = cpuid.get_feature_info().map_or(false, |info| info.has_pcid());
+        let invpcid = cpuid.get_extended_feature_info().map_or(false, |info| info.has_invpcid());
+        // PCIDE can only be set while the current PCID is 0
+        let supported = pcid && invpcid && read_cr3() & CR3_PCID_MASK == 0;
+        PCID_ENABLED.store(supported, Ordering::SeqCst);
+        if supported {
+            info!("[TLB] Using PCIDs");
+        }
+        supported
+    } else {
+        PCID_ENABLED.load(Ordering::SeqCst)
+    };
+    let mut cr4 = read_cr4() | CR4_PGE;
+    if supported {
+        cr4 |= CR4_PCIDE;
+    }
+    unsafe { write_cr4(cr4); }
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Address space switches
+///////////////////////////////////////////////////////////////////////////////////////////////////
+lazy_static! {
+    /// PCIDs handed back by destroyed address spaces, and the next one never used.
+    static ref FREE_PCIDS: spin::Mutex<(Vec<u16>, u16)> = spin::Mutex::new((Vec::new(), KERNEL_PCID + 1));
+}
+
+/// Returns a PCID for a new address space, or `None` if PCIDs are off or all of them are taken.
+/// Address spaces without one get their entries flushed on every switch.
+pub fn alloc_pcid() -> Option<u16> {
+    if !pcid_enabled() {
+        return None;
+    }
+    let mut free_pcids = FREE_PCIDS.lock();
+    let (free, next) = &mut *free_pcids;
+    free.pop().or_else(|| {
+        if *next > MAX_PCID {
+            return None;
+        }
+        *next += 1;
+        Some(*next - 1)
+    })
+}
+
+/// Hands a PCID back. Its entries have to be gone from every CPU, see `Invalidate::AddressSpace`.
+pub fn free_pcid(pcid: u16) {
+    FREE_PCIDS.lock().0.push(pcid);
+}
+
+/// What CR3 gets loaded with: a level 4 table and the PCID its entries are tagged with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageTableRoot {
+    frame: PhysFrame,
+    pcid: Option<u16>,
+}
+
+impl PageTableRoot {
+    pub fn new(frame: PhysFrame, pcid: Option<u16>) -> Self {
+        PageTableRoot { frame, pcid }
+    }
+
+    /// The page table kernel threads run on.
+    pub fn kernel() -> Self {
+        PageTableRoot::new(super::kernel_p4_frame(), Some(KERNEL_PCID))
+    }
+
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+}
+
+/// Loads `root` into CR3 unless it's active already. With PCIDs the entries cached for it stay
+/// valid, without them the switch flushes everything that isn't global.
+pub unsafe fn switch_to(root: PageTableRoot) {
+    let current = read_cr3();
+    let value = if pcid_enabled() {
+        match root.pcid {
+            Some(pcid) => root.frame.start_address().as_u64() | pcid as u64 | CR3_NO_FLUSH,
+            // Shares PCID 0 with the kernel table, so whatever is cached there has to go
+            None => root.frame.start_address().as_u64() | KERNEL_PCID as u64,
+        }
+    } else {
+        root.frame.start_address().as_u64() | (current & CR3_PCID_MASK) //Keeps the cache flags
+    };
+    if current & (CR3_ADDR_MASK | CR3_PCID_MASK) != value & (CR3_ADDR_MASK | CR3_PCID_MASK) {
+        write_cr3(value);
+    }
+}
+
+fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    value
+}
+
+unsafe fn write_cr3(value: u64) {
+    asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Invalidation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Which TLB entries to drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalidate {
+    /// Pages of the active address space
+    Local { start: VirtAddr, pages: u64 },
+    /// Pages every address space shares, like kernel stacks. They have to be mapped `GLOBAL`,
+    /// INVLPG only reaches other PCIDs for global entries.
+    Kernel { start: VirtAddr, pages: u64 },
+    /// Everything cached for the address space with this PCID
+    AddressSpace(u16),
+    All,
+}
+
+impl Invalidate {
+    /// Invalidates on the current CPU only.
+    pub fn run_local(self) {
+        use x86_64::instructions::tlb;
+        let pcid = pcid_enabled();
+        match self {
+            Invalidate::Local { start, pages } if pages <= FULL_FLUSH_THRESHOLD => {
+                for i in 0..pages {
+                    tlb::flush(start + i * Page::<Size4KiB>::SIZE);
+                }
+            }
+            Invalidate::Local { .. } => unsafe {
+                write_cr3(read_cr3() & !CR3_NO_FLUSH); //Drops the current PCID
+            },
+            // Global entries are dropped whatever PCID they were cached under
+            Invalidate::Kernel { start, pages } if pages <= FULL_FLUSH_THRESHOLD => {
+                for i in 0..pages {
+                    tlb::flush(start + i * Page::<Size4KiB>::SIZE);
+                }
+            }
+            Invalidate::Kernel { .. } | Invalidate::All if pcid => unsafe {
+                invpcid(INVPCID_ALL_CONTEXTS, 0, 0);
+            },
+            // Reloading CR3 keeps global entries, toggling PGE doesn't
+            Invalidate::Kernel { .. } | Invalidate::All => unsafe {
+                let cr4 = read_cr4();
+                write_cr4(cr4 & !CR4_PGE);
+                write_cr4(cr4);
+            },
+            Invalidate::AddressSpace(id) if pcid => unsafe {
+                invpcid(INVPCID_SINGLE_CONTEXT, id, 0);
+            },
+            // Without PCIDs switching away from an address space already flushed it
+            Invalidate::AddressSpace(_) => {}
+        }
+    }
+}
+
+/// Drops a single page cached for `pcid`, whether that address space is active or not.
+pub unsafe fn invalidate_page(pcid: u16, addr: VirtAddr) {
+    invpcid(INVPCID_ADDRESS, pcid, addr.as_u64());
+}
+
+unsafe fn invpcid(kind: u64, pcid: u16, addr: u64) {
+    let descriptor: [u64; 2] = [pcid as u64, addr];
+    asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(nostack, preserves_flags));
+}
+
+/// Collects the pages touched by a series of mapping changes, so they can be invalidated at once
+/// instead of one `flush()` per page.
+#[derive(Debug, Default)]
+pub struct FlushBatch {
+    range: Option<(VirtAddr, VirtAddr)>,
+}
+
+impl FlushBatch {
+    pub fn new() -> Self {
+        FlushBatch { range: None }
+    }
+
+    /// Adds the page of a `map_to` or `unmap` result to the batch.
+    pub fn add(&mut self, page: Page<Size4KiB>, flush: MapperFlush<Size4KiB>) {
+        flush.ignore();
+        let start = page.start_address();
+        let end = start + Page::<Size4KiB>::SIZE;
+        self.range = Some(match self.range {
+            Some((old_start, old_end)) => (old_start.min(start), old_end.max(end)),
+            None => (start, end),
+        });
+    }
+
+    fn pages(&self) -> Option<(VirtAddr, u64)> {
+        self.range.map(|(start, end)| (start, (end - start) / Page::<Size4KiB>::SIZE))
+    }
+
+    /// Invalidates the batch in the active address space of this CPU. Enough for mappings
+    /// that were only added, or that no other CPU can have cached.
+    pub fn flush_local(self) {
+        if let Some((start, pages)) = self.pages() {
+            Invalidate::Local { start, pages }.run_local();
+        }
+    }
+
+    /// Invalidates the batch of kernel pages on every CPU, in every address space.
+    pub fn shootdown_kernel(self) {
+        if let Some((start, pages)) = self.pages() {
+            shootdown(Invalidate::Kernel { start, pages }, CpuMask::ALL);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Shootdowns
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Only one shootdown runs at a time, the request fields below belong to it.
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static REQUEST_KIND: AtomicU64 = AtomicU64::new(0);
+static REQUEST_ADDR: AtomicU64 = AtomicU64::new(0);
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+/// CPUs that still have to invalidate for the running shootdown, indexed by CPU index.
+static PENDING: [AtomicBool; MAX_CPUS] = [ATOMIC_FALSE; MAX_CPUS];
+const ATOMIC_FALSE: AtomicBool = AtomicBool::new(false);
+
+fn store_request(invalidate: Invalidate) {
+    let (kind, addr, count) = match invalidate {
+        Invalidate::Local { start, pages } => (0, start.as_u64(), pages),
+        Invalidate::Kernel { start, pages } => (1, start.as_u64(), pages),
+        Invalidate::AddressSpace(pcid) => (2, 0, pcid as u64),
+        Invalidate::All => (3, 0, 0),
+    };
+    REQUEST_KIND.store(kind, Ordering::SeqCst);
+    REQUEST_ADDR.store(addr, Ordering::SeqCst);
+    REQUEST_COUNT.store(count, Ordering::SeqCst);
+}
+
+fn load_request() -> Invalidate {
+    let addr = REQUEST_ADDR.load(Ordering::SeqCst);
+    let count = REQUEST_COUNT.load(Ordering::SeqCst);
+    match REQUEST_KIND.load(Ordering::SeqCst) {
+        0 => Invalidate::Local { start: VirtAddr::new(addr), pages: count },
+        1 => Invalidate::Kernel { start: VirtAddr::new(addr), pages: count },
+        2 => Invalidate::AddressSpace(count as u16),
+        _ => Invalidate::All,
+    }
+}
+
+/// Invalidates on this CPU and on every other online CPU in `cpus`, and waits until all of them
+/// are done. Has to be used whenever a mapping other CPUs may have cached is removed or
+/// restricted, before its frame is reused.
+///
+/// Don't call this while holding a lock that other CPUs take with interrupts disabled,
+/// they couldn't answer then.
+pub fn shootdown(invalidate: Invalidate, cpus: CpuMask) {
+    invalidate.run_local();
+    let count = smp::cpu_count();
+    if count == 1 {
+        return;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Keep answering other shootdowns while waiting, or two CPUs could wait on each other
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+                break guard;
+            }
+            handle_shootdown();
+            core::sync::atomic::spin_loop_hint();
+        };
+
+        store_request(invalidate);
+        let this_cpu = smp::cpu_index();
+        let targets = (0..count).filter(|&cpu| cpu != this_cpu && cpus.contains(cpu));
+        for cpu in targets.clone() {
+            PENDING[cpu].store(true, Ordering::SeqCst);
+        }
+        let vector = InterruptIndex::TlbShootdown.as_u8();
+        for cpu in targets {
+            if let Some(apic_id) = smp::apic_id(cpu) {
+                unsafe { apic::send_ipi(IpiDestination::Apic(apic_id), IpiKind::Fixed(vector)); }
+            }
+        }
+        while PENDING[..count].iter().any(|pending| pending.load(Ordering::SeqCst)) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+/// Does the invalidation the running shootdown asked this CPU for, if any.
+/// Called from the shootdown IPI handler.
+pub fn handle_shootdown() {
+    let pending = &PENDING[smp::cpu_index()];
+    if pending.load(Ordering::SeqCst) {
+        load_request().run_local();
+        pending.store(false, Ordering::SeqCst);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// CPU tracking
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The CPUs that may have entries of an address space cached. CPUs are added when they
+/// switch to it, and only forget it once it is flushed from their TLB.
+#[derive(Debug, Default)]
+pub struct CpuTracker(AtomicU64);
+
+impl CpuTracker {
+    pub fn new() -> Self {
+        CpuTracker(AtomicU64::new(0))
+    }
+
+    pub fn mark(&self, cpu_index: usize) {
+        self.0.fetch_or(1 << cpu_index, Ordering::SeqCst);
+    }
+
+    pub fn cpus(&self) -> CpuMask {
+        CpuMask::from_bits(self.0.load(Ordering::SeqCst))
+    }
+}
@@ -2,9 +2,17 @@ pub mod thread;
 pub mod thread_switch;
 pub mod stack;
 pub mod scheduler;
+pub mod process;
+pub mod sync;
 use scheduler::Scheduler;
 
-static SCHEDULER: spin::Mutex<Option<Scheduler>> = spin::Mutex::new(None);
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+static SCHEDULER: spin::Once<Scheduler> = spin::Once::new();
+
+/// Frequency of the timer interrupt that drives preemption.
+pub const TICKS_PER_SECOND: u64 = 256;
 
 #[repr(u64)]
 pub enum SwitchReason {
@@ -14,14 +22,33 @@ pub enum SwitchReason {
     Exit,
 }
 
+/// Called from the timer interrupt, after the EOI has been sent.
+/// Charges the tick to the running thread and switches away once its time slice is used up.
+pub fn timer_tick() {
+    let expired = crate::smp::percpu::current().charge_tick();
+    if let Some(scheduler) = SCHEDULER.r#try() {
+        scheduler.tick();
+        send_kicks(scheduler.take_kicks());
+    }
+    if expired {
+        invoke_scheduler();
+    }
+}
+
+/// Preempts the current thread. Only call this with interrupts disabled,
+/// which is always the case from an interrupt handler.
 pub fn invoke_scheduler() {
-    let next = SCHEDULER
-        .try_lock()
-        .and_then(|mut scheduler| scheduler.as_mut().and_then(|s| s.schedule()));
-    if let Some((next_stack_pointer, prev_thread_id)) = next {
+    let scheduler = match SCHEDULER.r#try() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+    let next = scheduler.schedule();
+    send_kicks(scheduler.take_kicks());
+    if let Some((next_stack_pointer, next_root, prev_thread_id)) = next {
         unsafe {
             thread_switch::thread_switch_to(
                 next_stack_pointer,
+                next_root,
                 prev_thread_id,
                 SwitchReason::Paused,
             )
@@ -29,29 +56,166 @@ pub fn invoke_scheduler() {
     }
 }
 
+/// Returns the id of the thread that is running right now.
+pub fn current_thread_id() -> thread::ThreadId {
+    // The per-CPU copy avoids taking the scheduler lock, it's only unset before the scheduler exists.
+    // Interrupts stay off so the thread can't move to another CPU in between.
+    let thread_id = x86_64::instructions::interrupts::without_interrupts(|| {
+        crate::smp::percpu::current().current_thread()
+    });
+    thread_id.unwrap_or_else(|| with_scheduler(|s| s.current_thread_id()))
+}
+
 pub fn exit_thread() -> ! {
     synchronous_context_switch(SwitchReason::Exit).expect("can't exit last thread");
     unreachable!("finished thread continued");
 }
 
+/// Ends the current thread, recording `reason` for its process. Kernel threads just exit.
+pub fn exit_thread_with(reason: process::ExitReason) -> ! {
+    with_scheduler(|s| {
+        if let Some(process_id) = s.current_process_id() {
+            s.set_exit_reason(process_id, reason).expect("current process does not exist");
+        }
+    });
+    exit_thread();
+}
+
+/// Returns true if the current thread belongs to a process that was killed because another
+/// of its threads faulted. Such threads should call `exit_thread` once it's safe to do so.
+pub fn current_process_killed() -> bool {
+    with_scheduler(|s| s.current_process_id().map_or(false, |process_id| s.is_process_killed(process_id)))
+}
+
+/// Returns why a process ended, once its last thread is gone. Can only be asked once.
+pub fn exit_reason(process_id: process::ProcessId) -> Option<process::ExitReason> {
+    with_scheduler(|s| s.take_exit_reason(process_id))
+}
+
 pub fn yield_now() {
     let _ = synchronous_context_switch(SwitchReason::Yield);
 }
 
+/// Blocks the current thread until `wake` is called for it.
+///
+/// A wakeup that arrives before the thread actually blocks is remembered, so the usual pattern of
+/// checking a condition and then blocking can't miss it. Wakeups are not counted though, and one
+/// can be left over from earlier, so callers should check their condition again after this returns.
+/// Returns an error if there is no other thread to switch to.
+pub fn block_current() -> Result<(), ()> {
+    let pending = with_scheduler(|s| {
+        let current = s.current_thread_id();
+        assert!(!s.is_idle_thread(current), "the idle thread can't block");
+        s.take_wakeup(current)
+    });
+    if pending {
+        return Ok(());
+    }
+    synchronous_context_switch(SwitchReason::Blocked)
+}
+
+/// Puts the current thread to sleep for at least `duration`.
+///
+/// The thread is woken by a kernel timer, see `crate::timer`.
+pub fn sleep(duration: Duration) {
+    use crate::timer::{self, TimerAction};
+
+    assert!(!crate::interrupts::in_interrupt_context(), "can't sleep in interrupt context");
+    let timer_id = timer::add_timer(duration, TimerAction::Wake(current_thread_id()));
+    // Wakeups from anything other than the timer are ignored
+    while timer::is_pending(timer_id) {
+        if block_current().is_err() {
+            // Nothing else to run, so just wait for the next interrupt
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// Wakes up a thread blocked with `block_current`. If the thread isn't blocked (yet), its next
+/// call to `block_current` returns right away. Safe to call from interrupt handlers.
+pub fn wake(thread_id: thread::ThreadId) -> Result<(), ()> {
+    with_scheduler(|s| s.wake(thread_id))
+}
+
 fn synchronous_context_switch(reason: SwitchReason) -> Result<(), ()> {
-    let next = with_scheduler(|s| s.schedule());
-    match next {
-        Some((next_stack_pointer, prev_thread_id)) => unsafe {
-            thread_switch::thread_switch_to(next_stack_pointer, prev_thread_id, reason);
-            Ok(())
+    // A timer tick between `schedule` and the actual switch would schedule from the wrong stack
+    interrupts::without_interrupts(|| {
+        let next = with_scheduler(|s| s.schedule());
+        match next {
+            Some((next_stack_pointer, next_root, prev_thread_id)) => unsafe {
+                thread_switch::thread_switch_to(next_stack_pointer, next_root, prev_thread_id, reason);
+                Ok(())
+            },
+            None => Err(()),
+        }
+    })
+}
+
+/// Changes the priority of a thread that was already handed to the scheduler.
+pub fn set_priority(thread_id: thread::ThreadId, priority: thread::Priority) -> Result<(), ()> {
+    with_scheduler(|s| s.set_priority(thread_id, priority))
+}
+
+/// Restricts the CPUs a thread that was already handed to the scheduler may run on.
+/// Moves the current thread away right away if it's not allowed on this CPU anymore.
+pub fn set_affinity(thread_id: thread::ThreadId, affinity: thread::CpuMask) -> Result<(), ()> {
+    with_scheduler(|s| s.set_affinity(thread_id, affinity))?;
+    let must_move = interrupts::without_interrupts(|| {
+        thread_id == current_thread_id() && !affinity.contains(crate::smp::cpu_index())
+    });
+    if must_move {
+        yield_now();
+    }
+    Ok(())
+}
+
+/// Frees the stacks of exited threads and the address spaces of exited processes.
+/// Everything is put back if the memory globals are busy, so this never blocks.
+pub fn reap() {
+    let dead = with_scheduler(|s| s.take_dead());
+    if dead.is_empty() {
+        return;
+    }
+
+    let mapper = crate::memory::MAPPER.try_lock();
+    let frame_allocator = crate::memory::FRAME_ALLOCATOR.try_lock();
+    match (mapper, frame_allocator) {
+        (Some(mut mapper), Some(mut frame_allocator)) => {
+            let mapper = mapper.as_mut().unwrap();
+            let frame_allocator = frame_allocator.as_mut().unwrap();
+            for stack_bounds in dead.stacks {
+                unsafe { crate::memory::free_stack(stack_bounds, mapper, frame_allocator) }
+                    .expect("Failed to unmap thread stack!");
+            }
+            for process in dead.processes {
+                trace!("Freeing process {}", process.id().as_u64());
+                unsafe { process.into_address_space().destroy(frame_allocator); }
+            }
         },
-        None => Err(()),
+        _ => with_scheduler(|s| s.return_dead(dead)),
     }
 }
 
+/// Runs `f` on the scheduler. Interrupts are disabled meanwhile, so the timer interrupt never
+/// finds one of the scheduler locks taken by the thread it interrupted.
 pub fn with_scheduler<F, T>(f: F) -> T
 where
-    F: FnOnce(&mut Scheduler) -> T,
+    F: FnOnce(&Scheduler) -> T,
 {
-    f(SCHEDULER.lock().get_or_insert_with(Scheduler::new))
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.call_once(Scheduler::new);
+        let result = f(scheduler);
+        send_kicks(scheduler.take_kicks());
+        result
+    })
+}
+
+/// Sends a reschedule IPI to every CPU in `cpus`. Only with the scheduler locks released.
+fn send_kicks(cpus: thread::CpuMask) {
+    if cpus.is_empty() {
+        return;
+    }
+    for cpu in (0..crate::smp::MAX_CPUS).filter(|&cpu| cpus.contains(cpu)) {
+        crate::smp::send_reschedule_ipi(cpu);
+    }
 }
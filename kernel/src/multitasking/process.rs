@@ -0,0 +1,117 @@
+use crate::memory::AddressSpace;
+use crate::memory::tlb::{CpuTracker, PageTableRoot};
+use crate::multitasking::thread::ThreadId;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use x86_64::structures::paging::PhysFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    fn new() -> Self {
+        use core::sync::atomic::{AtomicU64, Ordering};
+        static NEXT_PROCESS_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_PROCESS_ID.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+/// Why a process ended, see `multitasking::exit_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The last thread called `exit` with this code
+    Exited(i64),
+    /// One of the threads caused a CPU exception in user mode
+    Fault(UserFault),
+}
+
+/// A CPU exception raised by user code, which the process gets killed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserFault {
+    pub exception: &'static str,
+    pub instruction_pointer: u64,
+    pub error_code: Option<u64>,
+    /// The address that was accessed, for page faults
+    pub address: Option<u64>,
+}
+
+/// A userspace program. Owns the address space its threads run in.
+#[derive(Debug)]
+pub struct Process {
+    id: ProcessId,
+    address_space: AddressSpace,
+    threads: BTreeSet<ThreadId>,
+    exit_reason: Option<ExitReason>,
+}
+
+impl Process {
+    pub fn new(address_space: AddressSpace) -> Self {
+        Process {
+            id: ProcessId::new(),
+            address_space,
+            threads: BTreeSet::new(),
+            exit_reason: None,
+        }
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn address_space_frame(&self) -> PhysFrame {
+        self.address_space.p4_frame()
+    }
+
+    pub fn page_table_root(&self) -> PageTableRoot {
+        self.address_space.root()
+    }
+
+    /// See `AddressSpace::active_cpus`.
+    pub fn active_cpus(&self) -> Arc<CpuTracker> {
+        self.address_space.active_cpus()
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    /// Consumes the process, returning its address space so it can be destroyed.
+    pub fn into_address_space(self) -> AddressSpace {
+        self.address_space
+    }
+
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.exit_reason
+    }
+
+    /// Returns true once a thread faulted. The remaining threads are terminated as soon as
+    /// they enter the kernel.
+    pub fn is_killed(&self) -> bool {
+        match self.exit_reason {
+            Some(ExitReason::Fault(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Records why the process is going away. A fault is never overwritten by a later exit,
+    /// otherwise the last reason counts.
+    pub(super) fn set_exit_reason(&mut self, reason: ExitReason) {
+        if !self.is_killed() {
+            self.exit_reason = Some(reason);
+        }
+    }
+
+    pub(super) fn add_thread(&mut self, thread_id: ThreadId) {
+        self.threads.insert(thread_id);
+    }
+
+    /// Returns true if this was the last thread of the process.
+    pub(super) fn remove_thread(&mut self, thread_id: ThreadId) -> bool {
+        self.threads.remove(&thread_id);
+        self.threads.is_empty()
+    }
+}
@@ -1,122 +1,742 @@
 use super::SwitchReason;
-use crate::multitasking::thread::{Thread, ThreadId};
-use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use crate::memory::StackBounds;
+use crate::memory::tlb::{CpuTracker, PageTableRoot};
+use crate::multitasking::thread::{CpuMask, Thread, ThreadId, Priority};
+use crate::multitasking::process::{ExitReason, Process, ProcessId};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::mem;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::smp::{cpu_index, percpu, MAX_CPUS};
 use x86_64::VirtAddr;
 
+/// The scheduler is split into separately locked parts, so CPUs only wait for each other when
+/// they touch the same thread or queue:
+///
+/// - the thread table, which is only written when threads come and go
+/// - the processes, together with what exited threads and processes left behind
+/// - the run queue and current thread of each CPU
+/// - every thread
+///
+/// Locks are taken in that order, run queues with the lower CPU index first. All of them are
+/// only held with interrupts disabled, so interrupt handlers can wait for them.
 pub struct Scheduler {
-    threads: BTreeMap<ThreadId, Thread>,
-    idle_thread_id: Option<ThreadId>,
-    current_thread_id: ThreadId,
-    paused_threads: VecDeque<ThreadId>,
-    blocked_threads: BTreeSet<ThreadId>,
-    wakeups: BTreeSet<ThreadId>,
+    threads: spin::RwLock<BTreeMap<ThreadId, ThreadRef>>,
+    process_table: spin::Mutex<ProcessTable>,
+    cpus: [spin::Mutex<CpuState>; MAX_CPUS], //Indexed by `smp::cpu_index`
+    online: AtomicU64,                       //CPUs registered with the scheduler
+    kicks: AtomicU64,                        //CPUs to send a reschedule IPI once the locks are released
+    quantum: AtomicU32,
+    ticks_since_boost: AtomicU32,
+    ticks_since_balance: AtomicU32,
+}
+
+/// Time slice in timer ticks of the highest level, see `multitasking::TICKS_PER_SECOND`.
+/// Every level below it gets twice the slice of the level above.
+pub const DEFAULT_QUANTUM: u32 = 4;
+
+/// Number of feedback queues. Level 0 is scheduled first.
+pub const LEVEL_COUNT: usize = 4;
+
+/// Every thread is moved back to the level of its priority this often,
+/// so demoted threads can't starve.
+const BOOST_INTERVAL: u32 = crate::multitasking::TICKS_PER_SECOND as u32;
+
+/// Queued threads are spread over the CPUs this often. Idle CPUs steal work in between.
+const BALANCE_INTERVAL: u32 = (crate::multitasking::TICKS_PER_SECOND / 4) as u32;
+
+type ThreadRef = Arc<spin::Mutex<ThreadState>>;
+
+/// A thread handed to the scheduler, with what the scheduler keeps track of on top.
+#[derive(Debug)]
+struct ThreadState {
+    thread: Thread,
+    root: PageTableRoot,                   //Page table the thread runs on
+    active_cpus: Option<Arc<CpuTracker>>,  //Of the address space, for threads of a process
+    idle: bool,
+    blocked: bool,
+    wakeup_pending: bool,                  //Woken up before it blocked
+}
+
+impl ThreadState {
+    fn new(thread: Thread) -> Self {
+        ThreadState {
+            thread,
+            root: PageTableRoot::kernel(),
+            active_cpus: None,
+            idle: false,
+            blocked: false,
+            wakeup_pending: false,
+        }
+    }
+}
+
+/// The feedback queues of one CPU.
+#[derive(Default)]
+struct RunQueue {
+    levels: [VecDeque<ThreadRef>; LEVEL_COUNT],
+}
+
+impl RunQueue {
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    fn push(&mut self, thread: ThreadRef, level: usize) {
+        self.levels[level].push_back(thread);
+    }
+
+    fn pop(&mut self) -> Option<ThreadRef> {
+        self.levels.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    fn remove(&mut self, thread: &ThreadRef) -> bool {
+        self.take_where_ref(|queued| Arc::ptr_eq(queued, thread)).is_some()
+    }
+
+    /// Takes the first thread `filter` accepts, starting at the highest level.
+    /// Locks every thread it looks at.
+    fn take_where(&mut self, mut filter: impl FnMut(&ThreadState) -> bool) -> Option<ThreadRef> {
+        self.take_where_ref(|queued| filter(&*queued.lock()))
+    }
+
+    fn take_where_ref(&mut self, mut filter: impl FnMut(&ThreadRef) -> bool) -> Option<ThreadRef> {
+        for queue in self.levels.iter_mut() {
+            if let Some(index) = queue.iter().position(|queued| filter(queued)) {
+                return queue.remove(index);
+            }
+        }
+        None
+    }
+
+    fn drain(&mut self) -> Vec<ThreadRef> {
+        self.levels.iter_mut().flat_map(|queue| queue.drain(..)).collect()
+    }
+}
+
+/// What the scheduler keeps per CPU.
+#[derive(Default)]
+struct CpuState {
+    queue: RunQueue,
+    current: Option<ThreadRef>, //Only `None` until the CPU is registered
+    idle: Option<ThreadRef>,
+}
+
+impl CpuState {
+    fn is_current(&self, thread: &ThreadRef) -> bool {
+        self.current.as_ref().map_or(false, |current| Arc::ptr_eq(current, thread))
+    }
+
+    fn is_idle(&self) -> bool {
+        self.idle.as_ref().map_or(false, |idle| self.is_current(idle))
+    }
+
+    /// Number of threads that want to run on this CPU, including the one running.
+    fn load(&self) -> usize {
+        let running = if self.current.is_some() && !self.is_idle() { 1 } else { 0 };
+        self.queue.len() + running
+    }
+}
+
+/// Locks the state of two different CPUs, the lower index first. Returns them in the order
+/// they were asked for.
+fn lock_pair(
+    cpus: &[spin::Mutex<CpuState>; MAX_CPUS],
+    a: usize,
+    b: usize,
+) -> (spin::MutexGuard<CpuState>, spin::MutexGuard<CpuState>) {
+    assert_ne!(a, b, "can't lock a CPU twice");
+    if a < b {
+        let first = cpus[a].lock();
+        (first, cpus[b].lock())
+    } else {
+        let first = cpus[b].lock();
+        (cpus[a].lock(), first)
+    }
+}
+
+/// Processes, together with what exited threads and processes left behind.
+#[derive(Default)]
+struct ProcessTable {
+    processes: BTreeMap<ProcessId, Process>,
+    exit_reasons: BTreeMap<ProcessId, ExitReason>, //Of processes that are gone
+    dead: DeadResources,
+}
+
+/// Memory of exited threads and processes, which can't be freed while the scheduler is locked.
+#[derive(Default)]
+pub struct DeadResources {
+    pub processes: Vec<Process>,
+    pub stacks: Vec<StackBounds>,
+}
+
+impl DeadResources {
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty() && self.stacks.is_empty()
+    }
 }
 
 impl Scheduler {
     pub fn new() -> Self {
-        let root_thread = Thread::create_root_thread();
-        let root_id = root_thread.id();
-        let mut threads = BTreeMap::new();
-        threads
-            .insert(root_id, root_thread)
-            .expect_none("map is not empty after creation");
-        Scheduler {
-            threads,
-            current_thread_id: root_id,
-            paused_threads: VecDeque::new(),
-            blocked_threads: BTreeSet::new(),
-            wakeups: BTreeSet::new(),
-            idle_thread_id: None,
-        }
-    }
-
-    fn next_thread(&mut self) -> Option<ThreadId> {
-        self.paused_threads.pop_front()
-    }
-
-    pub fn schedule(&mut self) -> Option<(VirtAddr, ThreadId)> {
-        let mut next_thread_id = self.next_thread();
-        if next_thread_id.is_none() && Some(self.current_thread_id) != self.idle_thread_id {
-            next_thread_id = self.idle_thread_id
-        }
-        if let Some(next_id) = next_thread_id {
-            let next_thread = self
-                .threads
-                .get_mut(&next_id)
-                .expect("next thread does not exist");
-            let next_stack_pointer = next_thread
+        let scheduler = Scheduler {
+            threads: spin::RwLock::new(BTreeMap::new()),
+            process_table: spin::Mutex::new(ProcessTable::default()),
+            cpus: Default::default(),
+            online: AtomicU64::new(0),
+            kicks: AtomicU64::new(0),
+            quantum: AtomicU32::new(DEFAULT_QUANTUM),
+            ticks_since_boost: AtomicU32::new(0),
+            ticks_since_balance: AtomicU32::new(0),
+        };
+        let mut root_thread = Thread::create_root_thread();
+        root_thread.set_cpu(cpu_index());
+        scheduler.start_cpu(ThreadState::new(root_thread));
+        scheduler
+    }
+
+    /// Registers the CPU this runs on, with `root` as the thread it's running right now.
+    fn start_cpu(&self, root: ThreadState) {
+        let cpu = cpu_index();
+        let (root_id, idle) = (root.thread.id(), root.idle);
+        let root = self.insert_thread(root);
+        {
+            let mut state = self.cpus[cpu].lock();
+            state.current.replace(root.clone()).expect_none("CPU was added twice");
+            if idle {
+                state.idle = Some(root);
+            }
+        }
+        self.online.fetch_or(1 << cpu, Ordering::SeqCst);
+        percpu::current().set_current_thread(root_id);
+        percpu::current().start_time_slice(self.quantum());
+    }
+
+    fn insert_thread(&self, state: ThreadState) -> ThreadRef {
+        let thread_id = state.thread.id();
+        let thread = Arc::new(spin::Mutex::new(state));
+        self.threads
+            .write()
+            .insert(thread_id, thread.clone())
+            .expect_none("thread already exists");
+        thread
+    }
+
+    fn thread(&self, thread_id: ThreadId) -> Option<ThreadRef> {
+        self.threads.read().get(&thread_id).cloned()
+    }
+
+    /// The thread running on the current CPU.
+    fn current_thread(&self) -> ThreadRef {
+        self.cpus[cpu_index()]
+            .lock()
+            .current
+            .clone()
+            .expect("CPU is not known to the scheduler")
+    }
+
+    /// Takes a queued thread that may run on `cpu` from the busiest other CPU.
+    /// Only one queue is locked at a time.
+    fn steal(&self, cpu: usize) -> Option<ThreadRef> {
+        let mut victims: Vec<(usize, usize)> = self
+            .online_cpus()
+            .filter(|&other| other != cpu)
+            .map(|other| (other, self.cpus[other].lock().queue.len()))
+            .collect();
+        victims.sort_by_key(|&(_, queued)| core::cmp::Reverse(queued));
+        for (victim, _) in victims {
+            let stolen = self.cpus[victim]
+                .lock()
+                .queue
+                .take_where(|state| state.thread.affinity().contains(cpu));
+            if stolen.is_some() {
+                return stolen;
+            }
+        }
+        None
+    }
+
+    /// Puts a thread at the back of the queue of its current level. It stays on the CPU it ran on
+    /// last if it's allowed to, so it finds its caches warm.
+    fn enqueue(&self, thread: ThreadRef) {
+        let (affinity, last_cpu) = {
+            let state = thread.lock();
+            (state.thread.affinity(), state.thread.cpu())
+        };
+        let cpu = if affinity.contains(last_cpu) && self.is_online(last_cpu) {
+            last_cpu
+        } else {
+            self.pick_cpu(affinity)
+        };
+        self.enqueue_on(thread, cpu);
+    }
+
+    /// Queues a thread on `cpu`, kicking that CPU out of its idle loop if needed.
+    /// Only locks the queue of `cpu`.
+    fn enqueue_on(&self, thread: ThreadRef, cpu: usize) {
+        let mut cpu_state = self.cpus[cpu].lock();
+        let level = {
+            let mut state = thread.lock();
+            state.thread.set_cpu(cpu);
+            state.thread.level()
+        };
+        cpu_state.queue.push(thread, level);
+        if cpu != cpu_index() && cpu_state.is_idle() {
+            self.kick(cpu);
+        }
+    }
+
+    fn kick(&self, cpu: usize) {
+        self.kicks.fetch_or(CpuMask::single(cpu).bits(), Ordering::SeqCst);
+    }
+
+    /// Takes the CPUs that have to run their scheduler. The IPIs may only be sent once the
+    /// locks are released, their handlers would find them taken otherwise.
+    pub(super) fn take_kicks(&self) -> CpuMask {
+        CpuMask::from_bits(self.kicks.swap(0, Ordering::SeqCst))
+    }
+
+    /// Returns the least loaded online CPU in `affinity`. If none of them is online yet, the
+    /// thread waits on the queue of the first one until it comes up.
+    fn pick_cpu(&self, affinity: CpuMask) -> usize {
+        self.online_cpus()
+            .filter(|&cpu| affinity.contains(cpu))
+            .min_by_key(|&cpu| self.load(cpu))
+            .or_else(|| (0..MAX_CPUS).find(|&cpu| affinity.contains(cpu)))
+            .expect("thread is not allowed to run on any CPU")
+    }
+
+    /// CPUs that were registered with the scheduler, see `add_cpu`.
+    fn online_cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_CPUS).filter(move |&cpu| self.is_online(cpu))
+    }
+
+    fn is_online(&self, cpu: usize) -> bool {
+        CpuMask::from_bits(self.online.load(Ordering::SeqCst)).contains(cpu)
+    }
+
+    /// Number of threads that want to run on `cpu`, including the one running there.
+    pub fn load(&self, cpu: usize) -> usize {
+        self.cpus[cpu].lock().load()
+    }
+
+    /// Moves queued threads from the busiest to the least busy CPU until their loads
+    /// differ by at most one, as far as the affinities allow.
+    fn balance(&self) {
+        loop {
+            let loads: Vec<(usize, usize)> = self.online_cpus().map(|cpu| (cpu, self.load(cpu))).collect();
+            let busiest = loads.iter().max_by_key(|&&(_, load)| load);
+            let idlest = loads.iter().min_by_key(|&&(_, load)| load);
+            let (busiest, idlest) = match (busiest, idlest) {
+                (Some(&(busiest, high)), Some(&(idlest, low))) if high > low + 1 => (busiest, idlest),
+                _ => return,
+            };
+            let (mut from, mut to) = lock_pair(&self.cpus, busiest, idlest);
+            if from.load() <= to.load() + 1 {
+                return; //Changed since the loads were read
+            }
+            let moved = match from.queue.take_where(|state| state.thread.affinity().contains(idlest)) {
+                Some(thread) => thread,
+                None => return,
+            };
+            let level = {
+                let mut state = moved.lock();
+                state.thread.set_cpu(idlest);
+                state.thread.level()
+            };
+            to.queue.push(moved, level);
+            if idlest != cpu_index() && to.is_idle() {
+                self.kick(idlest);
+            }
+        }
+    }
+
+    /// Picks the next thread to run. Returns its stack pointer, the page table it runs on
+    /// and the id of the thread that was running before.
+    pub fn schedule(&self) -> Option<(VirtAddr, PageTableRoot, ThreadId)> {
+        let cpu = cpu_index();
+        loop {
+            let popped = self.cpus[cpu].lock().queue.pop();
+            let next = popped.or_else(|| self.steal(cpu));
+
+            // Held until the thread is current, so `set_affinity` either finds it queued or running
+            let mut cpu_state = self.cpus[cpu].lock();
+            let next = match next {
+                Some(next) => next,
+                None if !cpu_state.is_idle() => cpu_state.idle.clone()?,
+                None => return None,
+            };
+            let mut state = next.lock();
+            if !state.thread.affinity().contains(cpu) {
+                // Its affinity changed while it was on the way here
+                drop(state);
+                drop(cpu_state);
+                self.enqueue(next);
+                continue;
+            }
+
+            let next_stack_pointer = state
+                .thread
                 .stack_pointer()
                 .take()
                 .expect("paused thread has no stack pointer");
-            let prev_thread_id = mem::replace(&mut self.current_thread_id, next_thread.id());
-            Some((next_stack_pointer, prev_thread_id))
-        } else {
-            None
+            state.thread.set_cpu(cpu);
+            if let Some(stack_bounds) = state.thread.stack_bounds() {
+                crate::userspace::set_kernel_stack(stack_bounds.end());
+            }
+            if let Some(active_cpus) = &state.active_cpus {
+                active_cpus.mark(cpu);
+            }
+            let next_root = state.root;
+            let next_id = state.thread.id();
+            percpu::current().start_time_slice(self.quantum() << state.thread.level());
+            drop(state);
+
+            cpu_state.current.replace(next).expect("CPU is not known to the scheduler");
+            let prev_thread_id = percpu::current()
+                .current_thread()
+                .expect("CPU is not known to the scheduler");
+            percpu::current().set_current_thread(next_id);
+            return Some((next_stack_pointer, next_root, prev_thread_id));
         }
     }
 
     pub(super) fn add_paused_thread(
-        &mut self,
+        &self,
         paused_stack_pointer: VirtAddr,
         paused_thread_id: ThreadId,
         switch_reason: SwitchReason,
     ) {
-        let paused_thread = self
-            .threads
-            .get_mut(&paused_thread_id)
-            .expect("paused thread does not exist");
-        paused_thread
+        let paused_thread = self.thread(paused_thread_id).expect("paused thread does not exist");
+        let mut state = paused_thread.lock();
+        state
+            .thread
             .stack_pointer()
             .replace(paused_stack_pointer)
             .expect_none("running thread should have stack pointer set to None");
-        if Some(paused_thread_id) == self.idle_thread_id {
+        if state.idle {
             return; // do nothing
         }
         match switch_reason {
-            SwitchReason::Paused | SwitchReason::Yield => {
-                self.paused_threads.push_back(paused_thread_id)
+            SwitchReason::Paused => {
+                // Used its whole time slice, so it's likely CPU bound
+                state.thread.demote();
+                drop(state);
+                self.enqueue(paused_thread);
+            }
+            SwitchReason::Yield => {
+                drop(state);
+                self.enqueue(paused_thread);
             }
             SwitchReason::Blocked => {
-                self.blocked_threads.insert(paused_thread_id);
-                self.check_for_wakeup(paused_thread_id);
+                // Gave up the CPU to wait on something, so it's likely I/O bound
+                state.thread.reset_level();
+                if mem::replace(&mut state.wakeup_pending, false) {
+                    drop(state);
+                    self.enqueue(paused_thread);
+                } else {
+                    state.blocked = true;
+                }
             }
             SwitchReason::Exit => {
-                let thread = self
-                    .threads
+                let (stack_bounds, process_id) = (state.thread.stack_bounds(), state.thread.process());
+                drop(state);
+                self.threads
+                    .write()
                     .remove(&paused_thread_id)
                     .expect("thread not found");
-                // TODO: free stack memory again
+                let mut table = self.process_table.lock();
+                // We're running on another stack already, it gets freed later on by `multitasking::reap`
+                if let Some(stack_bounds) = stack_bounds {
+                    table.dead.stacks.push(stack_bounds);
+                }
+                if let Some(process_id) = process_id {
+                    let process = table
+                        .processes
+                        .get_mut(&process_id)
+                        .expect("thread belongs to a process that does not exist");
+                    if process.remove_thread(paused_thread_id) {
+                        let process = table.processes.remove(&process_id).unwrap();
+                        let reason = process.exit_reason().unwrap_or(ExitReason::Exited(0));
+                        table.exit_reasons.insert(process_id, reason);
+                        table.dead.processes.push(process);
+                    }
+                }
             }
         }
     }
 
-    pub fn add_new_thread(&mut self, thread: Thread) {
-        let thread_id = thread.id();
-        self.threads
-            .insert(thread_id, thread)
-            .expect_none("thread already exists");
-        self.paused_threads.push_back(thread_id);
+    pub fn add_process(&self, process: Process) {
+        let process_id = process.id();
+        self.process_table
+            .lock()
+            .processes
+            .insert(process_id, process)
+            .expect_none("process already exists");
     }
 
-    pub fn set_idle_thread(&mut self, thread: Thread) {
-        let thread_id = thread.id();
-        self.threads
-            .insert(thread_id, thread)
-            .expect_none("thread already exists");
-        self.idle_thread_id
-            .replace(thread_id)
+    pub fn add_new_thread(&self, thread: Thread) {
+        let cpu = self.pick_cpu(thread.affinity());
+        let mut state = ThreadState::new(thread);
+        if let Some(process_id) = state.thread.process() {
+            let mut table = self.process_table.lock();
+            let process = table
+                .processes
+                .get_mut(&process_id)
+                .expect("thread belongs to a process that does not exist");
+            process.add_thread(state.thread.id());
+            state.root = process.page_table_root();
+            state.active_cpus = Some(process.active_cpus());
+        }
+        let thread = self.insert_thread(state);
+        self.enqueue_on(thread, cpu);
+    }
+
+    /// Sets the thread the current CPU runs when nothing else is runnable.
+    pub fn set_idle_thread(&self, mut thread: Thread) {
+        thread.set_affinity(CpuMask::single(cpu_index()));
+        thread.set_cpu(cpu_index());
+        let mut state = ThreadState::new(thread);
+        state.idle = true;
+        let thread = self.insert_thread(state);
+        self.cpus[cpu_index()]
+            .lock()
+            .idle
+            .replace(thread)
             .expect_none("idle thread should be set only once");
     }
 
+    /// Registers the CPU this runs on. Whatever it's running right now becomes
+    /// both its current and its idle thread.
+    pub fn add_cpu(&self, cpu_index: usize) {
+        let mut root_thread = Thread::create_root_thread();
+        root_thread.set_affinity(CpuMask::single(cpu_index));
+        root_thread.set_cpu(cpu_index);
+        let mut root = ThreadState::new(root_thread);
+        root.idle = true;
+        self.start_cpu(root);
+    }
+
+    /// Changes the priority of a thread, moving it to the queue of its new level right away.
+    pub fn set_priority(&self, thread_id: ThreadId, priority: Priority) -> Result<(), ()> {
+        let thread = self.thread(thread_id).ok_or(())?;
+        let cpu = {
+            let mut state = thread.lock();
+            state.thread.set_priority(priority);
+            state.thread.cpu()
+        };
+
+        let mut cpu_state = self.cpus[cpu].lock();
+        if cpu_state.queue.remove(&thread) {
+            let new_level = thread.lock().thread.level();
+            cpu_state.queue.push(thread, new_level);
+        }
+        Ok(())
+    }
+
+    pub fn priority(&self, thread_id: ThreadId) -> Option<Priority> {
+        self.thread(thread_id).map(|thread| thread.lock().thread.priority())
+    }
+
+    /// Restricts the CPUs a thread may run on. A queued thread is moved right away, a thread
+    /// running on another CPU it's no longer allowed on gets preempted there. The current thread
+    /// has to move itself, see `multitasking::set_affinity`.
+    pub fn set_affinity(&self, thread_id: ThreadId, affinity: CpuMask) -> Result<(), ()> {
+        let thread = self.thread(thread_id).ok_or(())?;
+        let cpu = {
+            let mut state = thread.lock();
+            if state.idle {
+                return Err(()); //Idle threads are bound to their CPU
+            }
+            state.thread.set_affinity(affinity);
+            state.thread.cpu()
+        };
+        if affinity.contains(cpu) {
+            return Ok(());
+        }
+
+        let queued = {
+            let mut cpu_state = self.cpus[cpu].lock();
+            let queued = cpu_state.queue.remove(&thread);
+            if !queued && cpu_state.is_current(&thread) && cpu != cpu_index() {
+                self.kick(cpu);
+            }
+            queued
+        };
+        if queued {
+            self.enqueue(thread);
+        }
+        Ok(())
+    }
+
+    pub fn affinity(&self, thread_id: ThreadId) -> Option<CpuMask> {
+        self.thread(thread_id).map(|thread| thread.lock().thread.affinity())
+    }
+
+    /// Moves every thread back to the level of its priority.
+    fn boost_all(&self) {
+        for thread in self.threads.read().values() {
+            thread.lock().thread.reset_level();
+        }
+        for cpu in 0..MAX_CPUS {
+            let mut cpu_state = self.cpus[cpu].lock();
+            for thread in cpu_state.queue.drain() {
+                let level = thread.lock().thread.level();
+                cpu_state.queue.push(thread, level);
+            }
+        }
+    }
+
+    /// Sets the length of a time slice in timer ticks. Takes effect on the next switch.
+    pub fn set_quantum(&self, ticks: u32) {
+        assert!(ticks > 0, "quantum has to be at least one tick");
+        self.quantum.store(ticks, Ordering::SeqCst);
+    }
+
+    pub fn quantum(&self) -> u32 {
+        self.quantum.load(Ordering::SeqCst)
+    }
+
+    /// Counts a timer tick towards boosting and balancing. Time slices are charged per CPU,
+    /// see `PerCpu::charge_tick`.
+    pub(super) fn tick(&self) {
+        if cpu_index() == 0 {
+            // Every CPU ticks, only count the intervals on one of them
+            if interval_elapsed(&self.ticks_since_boost, BOOST_INTERVAL) {
+                self.boost_all();
+            }
+            if interval_elapsed(&self.ticks_since_balance, BALANCE_INTERVAL) {
+                self.balance();
+            }
+        }
+    }
+
+    /// Returns the thread running on the current CPU.
     pub fn current_thread_id(&self) -> ThreadId {
-        self.current_thread_id
+        percpu::current().current_thread().expect("CPU is not known to the scheduler")
+    }
+
+    /// The process of the thread running on the current CPU, `None` for kernel threads.
+    pub fn current_process_id(&self) -> Option<ProcessId> {
+        self.current_thread().lock().thread.process()
+    }
+
+    /// Records why a process that is still around is going away, see `Process::set_exit_reason`.
+    pub fn set_exit_reason(&self, process_id: ProcessId, reason: ExitReason) -> Result<(), ()> {
+        let mut table = self.process_table.lock();
+        let process = table.processes.get_mut(&process_id).ok_or(())?;
+        process.set_exit_reason(reason);
+        Ok(())
+    }
+
+    /// Returns true if a thread of the process faulted, see `Process::is_killed`.
+    pub fn is_process_killed(&self, process_id: ProcessId) -> bool {
+        self.process_table.lock().processes.get(&process_id).map_or(false, Process::is_killed)
+    }
+
+    /// Takes the exit reason of a process whose last thread is gone.
+    pub fn take_exit_reason(&self, process_id: ProcessId) -> Option<ExitReason> {
+        self.process_table.lock().exit_reasons.remove(&process_id)
     }
 
-    fn check_for_wakeup(&mut self, thread_id: ThreadId) {
-        if self.wakeups.remove(&thread_id) {
-            assert!(self.blocked_threads.remove(&thread_id));
-            self.paused_threads.push_back(thread_id);
+    /// Takes the stacks of exited threads and the processes whose last thread exited,
+    /// so their memory can be freed.
+    pub(super) fn take_dead(&self) -> DeadResources {
+        mem::take(&mut self.process_table.lock().dead)
+    }
+
+    /// Gives resources back that couldn't be freed yet.
+    pub(super) fn return_dead(&self, dead: DeadResources) {
+        let mut table = self.process_table.lock();
+        table.dead.processes.extend(dead.processes);
+        table.dead.stacks.extend(dead.stacks);
+    }
+
+    /// Returns true if `thread_id` is the idle thread of any CPU.
+    pub fn is_idle_thread(&self, thread_id: ThreadId) -> bool {
+        self.thread(thread_id).map_or(false, |thread| thread.lock().idle)
+    }
+
+    /// Makes a blocked thread runnable again, or remembers the wakeup
+    /// if the thread hasn't blocked yet.
+    pub(super) fn wake(&self, thread_id: ThreadId) -> Result<(), ()> {
+        let thread = self.thread(thread_id).ok_or(())?;
+        let runnable = {
+            let mut state = thread.lock();
+            if state.blocked {
+                state.blocked = false;
+                true
+            } else {
+                state.wakeup_pending = true;
+                false
+            }
+        };
+        if runnable {
+            self.enqueue(thread);
         }
+        Ok(())
+    }
+
+    /// Consumes a wakeup that arrived while the thread was still running.
+    pub(super) fn take_wakeup(&self, thread_id: ThreadId) -> bool {
+        self.thread(thread_id)
+            .map_or(false, |thread| mem::replace(&mut thread.lock().wakeup_pending, false))
+    }
+}
+
+/// Counts a tick on `counter`. Returns true and starts over once `interval` ticks went by.
+fn interval_elapsed(counter: &AtomicU32, interval: u32) -> bool {
+    let ticks = counter.load(Ordering::Relaxed) + 1;
+    let elapsed = ticks >= interval;
+    counter.store(if elapsed { 0 } else { ticks }, Ordering::Relaxed);
+    elapsed
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+fn test_threads(count: usize) -> Vec<ThreadRef> {
+    (0..count)
+        .map(|_| Arc::new(spin::Mutex::new(ThreadState::new(Thread::create_root_thread()))))
+        .collect()
+}
+
+#[test_case]
+fn test_run_queue_levels() {
+    let threads = test_threads(4);
+    let mut queue = RunQueue::default();
+    queue.push(threads[0].clone(), 2);
+    queue.push(threads[1].clone(), 0);
+    queue.push(threads[2].clone(), 2);
+    queue.push(threads[3].clone(), 1);
+    assert_eq!(queue.len(), 4);
+
+    // Highest level first, first in first out within a level
+    for &expected in [1, 3, 0, 2].iter() {
+        assert!(Arc::ptr_eq(&queue.pop().unwrap(), &threads[expected]));
     }
+    assert!(queue.pop().is_none());
+}
+
+#[test_case]
+fn test_run_queue_take_where() {
+    let threads = test_threads(4);
+    let mut queue = RunQueue::default();
+    queue.push(threads[0].clone(), 3);
+    queue.push(threads[1].clone(), 1);
+    queue.push(threads[2].clone(), 1);
+    queue.push(threads[3].clone(), 0);
+
+    // The level is searched from the top, so the lower level thread isn't taken
+    let skipped = threads[3].lock().thread.id();
+    let taken = queue.take_where(|state| state.thread.id() != skipped).unwrap();
+    assert!(Arc::ptr_eq(&taken, &threads[1]));
+    assert!(queue.remove(&threads[0]));
+    assert!(!queue.remove(&threads[0]));
+    let drained = queue.drain();
+    assert_eq!(drained.len(), 2);
+    assert!(Arc::ptr_eq(&drained[0], &threads[3]));
+    assert!(Arc::ptr_eq(&drained[1], &threads[2]));
+    assert_eq!(queue.len(), 0);
 }
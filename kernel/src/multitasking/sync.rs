@@ -0,0 +1,266 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::thread::ThreadId;
+
+fn assert_can_block() {
+    assert!(
+        !crate::interrupts::in_interrupt_context(),
+        "blocking primitives can't be used from interrupt context"
+    );
+}
+
+/// Blocks the current thread. If there's no other thread to switch to, this returns right away
+/// and the caller just checks its condition again.
+fn block_or_yield() {
+    if super::block_current().is_err() {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Wait queue
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A list of threads blocked until something happens. Waking is allowed from interrupt
+/// context, waiting is not.
+pub struct WaitQueue {
+    waiters: spin::Mutex<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks until `condition` returns true. The condition is checked again after the thread is
+    /// registered, so a wakeup between checking and blocking can't be missed.
+    pub fn wait_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        assert_can_block();
+        loop {
+            if condition() {
+                return;
+            }
+            let thread_id = self.register_current();
+            if condition() {
+                self.unregister(thread_id);
+                return;
+            }
+            block_or_yield();
+            self.unregister(thread_id);
+        }
+    }
+
+    /// Wakes the thread that has been waiting the longest.
+    /// Returns false if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.with_waiters(|waiters| {
+            if waiters.is_empty() { None } else { Some(waiters.remove(0)) }
+        });
+        match waiter {
+            Some(thread_id) => {
+                let _ = super::wake(thread_id);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread, returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = self.with_waiters(|waiters| core::mem::take(waiters));
+        for &thread_id in waiters.iter() {
+            let _ = super::wake(thread_id);
+        }
+        waiters.len()
+    }
+
+    fn register_current(&self) -> ThreadId {
+        let thread_id = super::current_thread_id();
+        self.with_waiters(|waiters| waiters.push(thread_id));
+        thread_id
+    }
+
+    fn unregister(&self, thread_id: ThreadId) {
+        self.with_waiters(|waiters| waiters.retain(|&id| id != thread_id));
+    }
+
+    /// Interrupts are disabled so an IRQ handler waking this queue can't find it locked.
+    fn with_waiters<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Vec<ThreadId>) -> T,
+    {
+        interrupts::without_interrupts(|| f(&mut self.waiters.lock()))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Mutex
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A mutex that puts contending threads to sleep instead of spinning.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Locks the mutex, sleeping while another thread holds it.
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.waiters.wait_until(|| self.acquire());
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks the mutex if it's free. Never blocks, so it can be used from interrupt context.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Semaphore
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A counting semaphore. `release` may be called from interrupt context,
+/// which makes it useful for drivers waiting on an IRQ.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one unit, sleeping until one is available.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Takes one unit if available, never blocks.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.count.compare_exchange_weak(count, count - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => count = current,
+            }
+        }
+        false
+    }
+
+    /// Gives back one unit and wakes a waiter.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Condition variable
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A condition variable to use together with the sleeping `Mutex`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex and sleeps until notified, then locks it again.
+    /// Like any condition variable this can wake up spuriously, see `wait_while`.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        assert_can_block();
+        let mutex = guard.mutex;
+        // Register before unlocking, so a notify right after the unlock isn't lost
+        let thread_id = self.waiters.register_current();
+        drop(guard);
+        block_or_yield();
+        self.waiters.unregister(thread_id);
+        mutex.lock()
+    }
+
+    /// Sleeps as long as `condition` returns true.
+    pub fn wait_while<'a, T: ?Sized, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
@@ -1,5 +1,8 @@
 use crate::memory::{alloc_stack, StackBounds};
 use crate::multitasking::stack::Stack;
+use crate::multitasking::process::ProcessId;
+use crate::multitasking::scheduler::LEVEL_COUNT;
+use crate::smp::MAX_CPUS;
 use alloc::boxed::Box;
 use x86_64::{
     structures::paging::{mapper, FrameAllocator, Mapper, Size4KiB},
@@ -14,6 +17,11 @@ impl ThreadId {
         self.0
     }
 
+    /// Only for ids that came from `as_u64`, like the copy in the per-CPU data.
+    pub(crate) fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+
     fn new() -> Self {
         use core::sync::atomic::{AtomicU64, Ordering};
         static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
@@ -21,11 +29,70 @@ impl ThreadId {
     }
 }
 
+/// Scheduling priority. Decides the highest feedback queue level a thread can be on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    fn base_level(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// Set of CPUs a thread is allowed to run on, bit `n` stands for CPU index `n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+impl CpuMask {
+    pub const ALL: CpuMask = CpuMask(u64::MAX);
+
+    pub const fn empty() -> Self {
+        CpuMask(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CpuMask(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn single(cpu_index: usize) -> Self {
+        CpuMask(1 << cpu_index)
+    }
+
+    pub fn with(self, cpu_index: usize) -> Self {
+        CpuMask(self.0 | 1 << cpu_index)
+    }
+
+    pub fn contains(&self, cpu_index: usize) -> bool {
+        cpu_index < MAX_CPUS && self.0 & (1 << cpu_index) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 & (u64::MAX >> (64 - MAX_CPUS)) == 0
+    }
+}
+
 #[derive(Debug)]
 pub struct Thread {
     id: ThreadId,
     stack_pointer: Option<VirtAddr>,
     stack_bounds: Option<StackBounds>,
+    process: Option<ProcessId>,
+    priority: Priority,
+    level: usize,
+    affinity: CpuMask,
+    cpu: usize, //CPU the thread last ran or is queued on
 }
 
 impl Thread {
@@ -61,14 +128,25 @@ impl Thread {
             id: ThreadId::new(),
             stack_pointer: Some(stack_pointer),
             stack_bounds: Some(stack_bounds),
+            process: None,
+            priority: Priority::Normal,
+            level: Priority::Normal.base_level(),
+            affinity: CpuMask::ALL,
+            cpu: 0,
         }
     }
 
+    /// Wraps whatever a CPU is running when it first enters the scheduler.
     pub(super) fn create_root_thread() -> Self {
         Thread {
-            id: ThreadId(0),
+            id: ThreadId::new(),
             stack_pointer: None,
             stack_bounds: None,
+            process: None,
+            priority: Priority::Normal,
+            level: Priority::Normal.base_level(),
+            affinity: CpuMask::ALL,
+            cpu: 0,
         }
     }
 
@@ -76,6 +154,65 @@ impl Thread {
         self.id
     }
 
+    /// The process this thread belongs to, kernel threads don't have one.
+    pub fn process(&self) -> Option<ProcessId> {
+        self.process
+    }
+
+    /// Makes the thread run in the address space of `process`.
+    /// Has to be called before the thread is handed to the scheduler.
+    pub fn set_process(&mut self, process: ProcessId) {
+        self.process = Some(process);
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Sets the priority and moves the thread to the matching level.
+    /// Use `multitasking::set_priority` for threads that were handed to the scheduler already.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+        self.level = priority.base_level();
+    }
+
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    /// Restricts the CPUs the thread may run on.
+    /// Use `multitasking::set_affinity` for threads that were handed to the scheduler already.
+    pub fn set_affinity(&mut self, affinity: CpuMask) {
+        assert!(!affinity.is_empty(), "thread has to be allowed on at least one CPU");
+        self.affinity = affinity;
+    }
+
+    /// The CPU the thread last ran on, or whose run queue it's on.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub(super) fn set_cpu(&mut self, cpu_index: usize) {
+        self.cpu = cpu_index;
+    }
+
+    /// Current feedback queue level, changes as the thread uses up or gives up its time slices.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub(super) fn demote(&mut self) {
+        self.level = core::cmp::min(self.level + 1, LEVEL_COUNT - 1);
+    }
+
+    pub(super) fn reset_level(&mut self) {
+        self.level = self.priority.base_level();
+    }
+
+    pub fn stack_bounds(&self) -> Option<StackBounds> {
+        self.stack_bounds
+    }
+
     pub(super) fn stack_pointer(&mut self) -> &mut Option<VirtAddr> {
         &mut self.stack_pointer
     }
@@ -1,5 +1,7 @@
 use x86_64::VirtAddr;
 
+use crate::memory::tlb::{self, PageTableRoot};
+
 use super::{
     thread::ThreadId,
     SwitchReason,
@@ -10,9 +12,13 @@ global_asm!(include_str!("thread_switch.s"));
 
 pub unsafe fn thread_switch_to(
     new_stack_pointer: VirtAddr,
+    new_root: PageTableRoot,
     prev_thread_id: ThreadId,
     switch_reason: SwitchReason,
 ) {
+    // Kernel stacks are mapped in every address space, so it's fine to switch before the stack
+    tlb::switch_to(new_root);
+
     llvm_asm!(
         "call asm_thread_switch"
         :
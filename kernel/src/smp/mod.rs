@@ -0,0 +1,316 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use x86_64::{
+    structures::paging::{Mapper, MapperAllSizes, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+use crate::hardware::hpet;
+use crate::interrupts::apic::{self, IpiDestination, IpiKind};
+use crate::interrupts::InterruptIndex;
+use crate::memory::{tlb, PHYSICAL_MEMORY_OFFSET};
+use crate::multitasking::thread::CpuMask;
+
+pub mod percpu;
+
+global_asm!(include_str!("trampoline.s"));
+
+/// Most CPUs the kernel will bring up, including the BSP.
+pub const MAX_CPUS: usize = 16;
+
+/// Physical address the trampoline is copied to. Has to match `TRAMPOLINE_BASE` in
+/// `trampoline.s`, and be page aligned and below 1 MiB so it can be used as a SIPI vector.
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+/// Size of the stack every AP starts on, in pages.
+const AP_STACK_PAGES: u64 = 16;
+
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+/// APIC id of every CPU that is online, indexed by CPU index. The BSP is always index 0.
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [ATOMIC_NO_CPU; MAX_CPUS];
+const ATOMIC_NO_CPU: AtomicU32 = AtomicU32::new(u32::MAX);
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by an AP once it's done setting itself up, so the BSP can start the next one.
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+/// The boot attempt an AP has to present to `ap_entry` to be let in, 0 while none is open.
+/// Every attempt gets a new number, so an AP that shows up after the BSP gave up on it can't
+/// take the CPU index and stack of the next one.
+static BOOT_ATTEMPT: AtomicU64 = AtomicU64::new(0);
+static NEXT_BOOT_ATTEMPT: AtomicU64 = AtomicU64::new(1);
+/// CPU index of the AP that claims `BOOT_ATTEMPT`.
+static BOOT_CPU_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// CPU identification
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Returns the APIC id of the CPU this runs on.
+pub fn current_apic_id() -> u32 {
+    apic::local_apic_id()
+}
+
+/// Returns the index of the CPU this runs on, between 0 and `cpu_count`.
+/// Unlike APIC ids these are contiguous, so they can be used to index per-CPU arrays.
+pub fn cpu_index() -> usize {
+    percpu::current().cpu_index()
+}
+
+/// Number of CPUs that are online.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the APIC id of the CPU with index `cpu_index`, if it is online or starting up.
+pub fn apic_id(cpu_index: usize) -> Option<u32> {
+    match CPU_APIC_IDS.get(cpu_index)?.load(Ordering::SeqCst) {
+        u32::MAX => None,
+        apic_id => Some(apic_id),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// AP startup
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Starts every AP in `apic_ids`, one after the other. Needs the HPET for the delays between
+/// the startup IPIs and the scheduler to hand the APs to.
+pub fn init(apic_ids: Vec<u32>) {
+    CPU_APIC_IDS[0].store(current_apic_id(), Ordering::SeqCst);
+    if apic_ids.is_empty() {
+        return;
+    }
+
+    identity_map_trampoline();
+    unsafe { copy_trampoline(); }
+
+    for apic_id in apic_ids {
+        let cpu_index = CPU_COUNT.load(Ordering::SeqCst);
+        if cpu_index >= MAX_CPUS {
+            warn!("[SMP] More than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+        if boot_ap(apic_id, cpu_index) {
+            debug!("[SMP] CPU {} (APIC id {}) is online", cpu_index, apic_id);
+        } else {
+            error!("[SMP] CPU with APIC id {} did not start!", apic_id);
+        }
+    }
+
+    unmap_trampoline();
+    info!("[SMP] {} CPUs online", cpu_count());
+}
+
+/// The trampoline switches on paging while running at `TRAMPOLINE_BASE`,
+/// so that page has to be identity mapped in the kernel page table.
+fn identity_map_trampoline() {
+    let mut mapper = crate::memory::MAPPER.lock();
+    let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().unwrap();
+
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+    if mapper.translate_addr(page.start_address()) == Some(frame.start_address()) {
+        return; //Already identity mapped by the bootloader
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_allocator.as_mut().unwrap())
+            .expect("Failed to identity map the AP trampoline!")
+            .flush();
+    }
+}
+
+fn unmap_trampoline() {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+    // Leaves the mapping alone if the bootloader made it, unmap fails on huge pages then
+    let unmapped = match crate::memory::MAPPER.lock().as_mut().unwrap().unmap(page) {
+        Ok((_, flush)) => {
+            flush.ignore();
+            true
+        }
+        Err(_) => false,
+    };
+    // The APs went through the trampoline, so they may still have it cached. The mapping isn't
+    // global and INVLPG wouldn't reach other PCIDs, but this only happens once
+    if unmapped {
+        tlb::shootdown(tlb::Invalidate::All, CpuMask::ALL);
+    }
+}
+
+/// Returns the physical memory mapping of the trampoline copy of `symbol`.
+unsafe fn trampoline_ptr<T>(symbol: &u8) -> *mut T {
+    let offset = symbol as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+    (PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + TRAMPOLINE_BASE + offset) as *mut T
+}
+
+unsafe fn copy_trampoline() {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline doesn't fit in a page");
+    core::ptr::copy_nonoverlapping(start, trampoline_ptr(&ap_trampoline_start), len);
+
+    // The trampoline loads CR3 while still in 32 bit mode
+    let p4_addr = crate::memory::kernel_p4_frame().start_address().as_u64();
+    assert!(p4_addr <= u32::MAX as u64, "kernel level 4 table is above 4 GiB");
+    trampoline_ptr::<u64>(&ap_trampoline_cr3).write_volatile(p4_addr);
+    trampoline_ptr::<u64>(&ap_trampoline_entry).write_volatile(ap_entry as u64);
+}
+
+/// Sends INIT-SIPI-SIPI to `apic_id` and waits for it to come up.
+fn boot_ap(apic_id: u32, cpu_index: usize) -> bool {
+    let stack = {
+        let mut mapper = crate::memory::MAPPER.lock();
+        let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+        crate::memory::alloc_stack(AP_STACK_PAGES, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("Failed to allocate an AP stack!")
+    };
+    let attempt = NEXT_BOOT_ATTEMPT.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        trampoline_ptr::<u64>(&ap_trampoline_stack).write_volatile(stack.end().as_u64());
+        trampoline_ptr::<u64>(&ap_trampoline_arg).write_volatile(attempt);
+    }
+    BOOT_CPU_INDEX.store(cpu_index, Ordering::SeqCst);
+    CPU_APIC_IDS[cpu_index].store(apic_id, Ordering::SeqCst);
+    AP_READY.store(false, Ordering::SeqCst);
+    BOOT_ATTEMPT.store(attempt, Ordering::SeqCst);
+
+    let sipi_page = (TRAMPOLINE_BASE >> 12) as u8;
+    unsafe {
+        apic::send_ipi(IpiDestination::Apic(apic_id), IpiKind::Init);
+        hpet::busy_wait(Duration::from_millis(10));
+        for _ in 0..2 {
+            apic::send_ipi(IpiDestination::Apic(apic_id), IpiKind::Startup(sipi_page));
+            if wait_for_ap(Duration::from_millis(1)) {
+                return true;
+            }
+        }
+    }
+    // Some CPUs take their time
+    if wait_for_ap(Duration::from_millis(100)) {
+        return true;
+    }
+
+    // Close the attempt. If the AP claimed it in the meantime it's running kernel code on its
+    // stack already, and only slow to finish
+    if BOOT_ATTEMPT.compare_exchange(attempt, 0, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        while !AP_READY.load(Ordering::SeqCst) {
+            core::sync::atomic::spin_loop_hint();
+        }
+        return true;
+    }
+
+    // It may still be in the trampoline, INIT puts it back to waiting for a SIPI before
+    // the trampoline data and the stack are reused
+    unsafe { apic::send_ipi(IpiDestination::Apic(apic_id), IpiKind::Init); }
+    hpet::busy_wait(Duration::from_millis(10));
+    CPU_APIC_IDS[cpu_index].store(u32::MAX, Ordering::SeqCst);
+    let mut mapper = crate::memory::MAPPER.lock();
+    let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+    unsafe { crate::memory::free_stack(stack, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()) }
+        .expect("Failed to free the stack of an AP that didn't start!");
+    false
+}
+
+fn wait_for_ap(timeout: Duration) -> bool {
+    let deadline = hpet::read_main_counter() + hpet::duration_to_ticks(timeout);
+    while hpet::read_main_counter() < deadline {
+        if AP_READY.load(Ordering::SeqCst) {
+            return true;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    AP_READY.load(Ordering::SeqCst)
+}
+
+/// Where every AP ends up after the trampoline, on its own stack and the kernel page table.
+/// `attempt` is the boot attempt the BSP started it with.
+extern "C" fn ap_entry(attempt: u64) -> ! {
+    if BOOT_ATTEMPT.compare_exchange(attempt, 0, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        // The BSP gave up on this CPU and is about to send it an INIT
+        halt();
+    }
+    let cpu_index = BOOT_CPU_INDEX.load(Ordering::SeqCst);
+
+    crate::enable_cpu_extensions();
+    let tss = crate::gdt::init_ap();
+    unsafe { percpu::init(cpu_index, current_apic_id(), tss); }
+    tlb::init();
+    crate::memory::mmio::init();
+    crate::interrupts::init_idt();
+    crate::gdt::setup_usermode_gdt();
+    crate::userspace::syscall::init();
+    unsafe { apic::enable_apic(); }
+
+    // The BSP only starts one AP at a time, the index is the next free one
+    CPU_COUNT
+        .compare_exchange(cpu_index, cpu_index + 1, Ordering::SeqCst, Ordering::SeqCst)
+        .expect("AP was started with a CPU index that is taken");
+    crate::multitasking::with_scheduler(|s| s.add_cpu(cpu_index));
+    unsafe { crate::interrupts::apic_timer::start_scheduler_tick(); }
+    AP_READY.store(true, Ordering::SeqCst);
+
+    // This thread is the idle thread of this CPU now
+    x86_64::instructions::interrupts::enable();
+    loop {
+        crate::multitasking::reap();
+        x86_64::instructions::hlt();
+        crate::multitasking::yield_now();
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IPIs
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Makes `cpu_index` run its scheduler, e.g. to get it out of its idle loop once there's work
+/// queued for it.
+pub fn send_reschedule_ipi(cpu_index: usize) {
+    let apic_id = match apic_id(cpu_index) {
+        Some(apic_id) => apic_id,
+        None => return, //Not online (yet)
+    };
+    let vector = InterruptIndex::Reschedule.as_u8();
+    unsafe { apic::send_ipi(IpiDestination::Apic(apic_id), IpiKind::Fixed(vector)); }
+}
+
+/// Set once a CPU started stopping the others, see `halt_other_cpus`.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Stops every other CPU with an NMI, so nothing keeps running after a panic.
+/// If another CPU got here first, this one is stopped instead.
+pub fn halt_other_cpus() {
+    if cpu_count() == 1 {
+        return;
+    }
+    if HALTING.swap(true, Ordering::SeqCst) {
+        halt(); //Someone else is panicking already, their NMI is on the way
+    }
+    unsafe { apic::send_ipi(IpiDestination::AllButSelf, IpiKind::Nmi); }
+}
+
+/// Returns true once `halt_other_cpus` was called. The NMI handler uses this to tell
+/// a halt request apart from a hardware NMI.
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::SeqCst)
+}
+
+/// Stops the current CPU for good.
+pub fn halt() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,145 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::multitasking::thread::ThreadId;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Offsets into `PerCpu` used from assembly, have to match the ones in `userspace/syscall.s`.
+pub const PERCPU_USER_RSP: usize = 8;
+pub const PERCPU_KERNEL_RSP: usize = 16;
+
+/// Data that every CPU has its own copy of. The GS base points at the block of the CPU
+/// while running in the kernel, user code runs with its own GS base and every entry into
+/// the kernel from ring 3 does a `swapgs`.
+///
+/// Only the owning CPU ever touches a block, so plain fields are fine as long as
+/// interrupts can't interfere. The run queues stay in the `Scheduler`, indexed by `cpu_index`.
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: *const PerCpu,            //gs:[0], so the block can be found without reading the MSR
+    user_rsp: UnsafeCell<u64>,          //gs:[8], scratch slot for `asm_syscall_entry`
+    kernel_rsp: UnsafeCell<u64>,        //gs:[16], stack `asm_syscall_entry` switches to
+    cpu_index: usize,
+    apic_id: u32,
+    tss: *mut TaskStateSegment,
+    current_thread: AtomicU64,          //0 until the scheduler runs on this CPU
+    interrupt_depth: AtomicUsize,
+    slice_remaining: AtomicU32,         //Timer ticks left for the current thread
+}
+
+static mut BSP_PERCPU: PerCpu = PerCpu::new(0, 0, core::ptr::null_mut());
+
+impl PerCpu {
+    const fn new(cpu_index: usize, apic_id: u32, tss: *mut TaskStateSegment) -> Self {
+        PerCpu {
+            self_ptr: core::ptr::null(),
+            user_rsp: UnsafeCell::new(0),
+            kernel_rsp: UnsafeCell::new(0),
+            cpu_index,
+            apic_id,
+            tss,
+            current_thread: AtomicU64::new(0),
+            interrupt_depth: AtomicUsize::new(0),
+            slice_remaining: AtomicU32::new(0),
+        }
+    }
+
+    /// Index of this CPU, see `smp::cpu_index`.
+    pub fn cpu_index(&self) -> usize {
+        self.cpu_index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn tss(&self) -> *mut TaskStateSegment {
+        self.tss
+    }
+
+    /// Sets the stack syscalls switch to. Interrupts use the TSS instead.
+    pub fn set_kernel_stack(&self, stack_end: VirtAddr) {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            *self.kernel_rsp.get() = stack_end.as_u64();
+        });
+    }
+
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(unsafe { *self.kernel_rsp.get() })
+    }
+
+    /// The thread running on this CPU, `None` until the scheduler picked one.
+    pub fn current_thread(&self) -> Option<ThreadId> {
+        match self.current_thread.load(Ordering::SeqCst) {
+            0 => None,
+            id => Some(ThreadId::from_u64(id)),
+        }
+    }
+
+    pub fn set_current_thread(&self, thread_id: ThreadId) {
+        self.current_thread.store(thread_id.as_u64(), Ordering::SeqCst);
+    }
+
+    pub fn enter_interrupt(&self) {
+        self.interrupt_depth.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn leave_interrupt(&self) {
+        self.interrupt_depth.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.load(Ordering::SeqCst) > 0
+    }
+
+    /// Gives the thread that was just switched to a time slice of `ticks` timer ticks.
+    pub fn start_time_slice(&self, ticks: u32) {
+        self.slice_remaining.store(ticks, Ordering::SeqCst);
+    }
+
+    /// Charges a timer tick to the running thread. Returns true once its time slice is used up,
+    /// and keeps doing so until the next one starts.
+    pub fn charge_tick(&self) -> bool {
+        let remaining = self.slice_remaining.load(Ordering::SeqCst).saturating_sub(1);
+        self.slice_remaining.store(remaining, Ordering::SeqCst);
+        remaining == 0
+    }
+}
+
+/// Sets up the per-CPU block of the calling CPU and points the GS base at it.
+/// Has to run on every CPU before anything calls `current`.
+pub unsafe fn init(cpu_index: usize, apic_id: u32, tss: *mut TaskStateSegment) {
+    let percpu: &'static mut PerCpu = if cpu_index == 0 {
+        BSP_PERCPU = PerCpu::new(0, apic_id, tss);
+        &mut BSP_PERCPU
+    } else {
+        alloc::boxed::Box::leak(alloc::boxed::Box::new(PerCpu::new(cpu_index, apic_id, tss)))
+    };
+    let ptr = percpu as *const PerCpu;
+    percpu.self_ptr = ptr;
+
+    Msr::new(IA32_GS_BASE).write(ptr as u64);
+    Msr::new(IA32_KERNEL_GS_BASE).write(0); //Swapped in when entering ring 3
+}
+
+/// Returns the per-CPU block of the calling CPU.
+/// Whatever runs with it has to stay on this CPU, so keep interrupts disabled if it matters.
+pub fn current() -> &'static PerCpu {
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+/// Whether the GS base currently points at a per-CPU block. Only needed where the privilege
+/// level of the interrupted code doesn't say that, like in between `syscall` and its `swapgs`.
+pub fn kernel_gs_active() -> bool {
+    unsafe { Msr::new(IA32_GS_BASE).read() != 0 }
+}
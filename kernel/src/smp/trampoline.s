@@ -0,0 +1,86 @@
+//; in src/smp/trampoline.s
+//; use intel asm syntax
+.intel_syntax noprefix
+
+//; Application processors start in real mode at TRAMPOLINE_BASE, where `smp::boot_ap` copies
+//; everything between `ap_trampoline_start` and `ap_trampoline_end`. Addresses are absolute and
+//; calculated from the offset to `ap_trampoline_start`, because this code doesn't run where it
+//; was linked. The data slots at the end are filled in by the BSP before every SIPI.
+.set TRAMPOLINE_BASE, 0x8000
+
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_arg
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lgdt [TRAMPOLINE_BASE + ap_trampoline_gdt_ptr - ap_trampoline_start]
+
+    mov eax, cr4                //; enable PAE and global pages
+    or eax, (1 << 5) | (1 << 7)
+    mov cr4, eax
+
+    mov eax, [TRAMPOLINE_BASE + ap_trampoline_cr3 - ap_trampoline_start]
+    mov cr3, eax
+
+    mov ecx, 0xC0000080         //; enable long mode and no-execute in EFER
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    mov eax, cr0                //; enable paging and protection at once, straight into long mode
+    or eax, (1 << 31) | (1 << 0)
+    mov cr0, eax
+
+    //; jmp 0x08:ap_trampoline_long_mode, written out because the target is absolute
+    .byte 0x66, 0xEA
+    .long TRAMPOLINE_BASE + ap_trampoline_long_mode - ap_trampoline_start
+    .word 0x08
+
+.code64
+ap_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [TRAMPOLINE_BASE + ap_trampoline_stack - ap_trampoline_start]
+    mov rdi, [TRAMPOLINE_BASE + ap_trampoline_arg - ap_trampoline_start]
+    mov rax, [TRAMPOLINE_BASE + ap_trampoline_entry - ap_trampoline_start]
+    call rax                    //; never returns
+ap_trampoline_halt:
+    hlt
+    jmp ap_trampoline_halt
+
+.align 16
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF    //; 64 bit code, selector 0x08
+    .quad 0x00CF92000000FFFF    //; data, selector 0x10
+ap_trampoline_gdt_ptr:
+    .word ap_trampoline_gdt_ptr - ap_trampoline_gdt - 1
+    .long TRAMPOLINE_BASE + ap_trampoline_gdt - ap_trampoline_start
+
+.align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_arg:
+    .quad 0
+ap_trampoline_end:
@@ -0,0 +1,29 @@
+use super::ClockSource;
+use crate::hardware::hpet;
+
+/// The HPET main counter. Always there when the HPET is, but every read is an MMIO access.
+pub struct HpetClock;
+
+pub static HPET_CLOCK: HpetClock = HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "HPET"
+    }
+
+    fn is_available(&self) -> bool {
+        hpet::is_initialized()
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn frequency(&self) -> u64 {
+        hpet::frequency()
+    }
+
+    fn read(&self) -> u64 {
+        hpet::read_main_counter()
+    }
+}
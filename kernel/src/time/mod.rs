@@ -0,0 +1,233 @@
+use alloc::vec::Vec;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicI64, Ordering};
+use core::time::Duration;
+
+use crate::hardware::rtc::{self, DateTime};
+
+pub mod tsc;
+pub mod hpet;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Clock sources
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A free running counter that can be used as the monotonic clock.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether this source can be used on this machine. Only called during `init`.
+    fn is_available(&self) -> bool;
+
+    /// Higher is better. The best available source is picked at boot.
+    fn rating(&self) -> u32;
+
+    /// Frequency of the counter in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Current value of the counter. Has to be monotonic and safe to call from interrupt handlers.
+    fn read(&self) -> u64;
+}
+
+lazy_static! {
+    static ref CLOCK_SOURCES: spin::Mutex<Vec<&'static dyn ClockSource>> =
+        spin::Mutex::new(vec![&tsc::TSC_CLOCK as &dyn ClockSource, &hpet::HPET_CLOCK]);
+    static ref CLOCK: spin::RwLock<Option<&'static dyn ClockSource>> = spin::RwLock::new(None);
+}
+
+/// Makes another clock source available. Has to be called before `init`.
+pub fn register_clock_source(source: &'static dyn ClockSource) {
+    CLOCK_SOURCES.lock().push(source);
+}
+
+/// Returns the name of the clock source in use, if `init` has run.
+pub fn clock_source_name() -> Option<&'static str> {
+    CLOCK.read().map(|source| source.name())
+}
+
+/// Picks the best clock source and seeds the wall clock from the CMOS.
+/// The HPET and the APIC timer calibration have to be done before this.
+pub fn init() {
+    let best = CLOCK_SOURCES
+        .lock()
+        .iter()
+        .copied()
+        .filter(|source| source.is_available())
+        .max_by_key(|source| source.rating())
+        .expect("No usable clock source found!");
+    info!("[TIME] Using {} as clock source ({} Hz)", best.name(), best.frequency());
+    *CLOCK.write() = Some(best);
+
+    let date_time = rtc::read_date_time();
+    set_system_time(SystemTime(unix_time_from_date_time(&date_time)));
+    info!("[TIME] Current date: {:?}", date_time);
+}
+
+/// Nanoseconds since the clock source started counting.
+fn now_nanos() -> u64 {
+    let source = CLOCK.read().expect("time::init has not been called yet!");
+    (source.read() as u128 * NANOS_PER_SEC / source.frequency() as u128) as u64
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Instant
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A point in time on the monotonic clock. Only useful to compare to other instants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64); //Nanoseconds
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(now_nanos())
+    }
+
+    /// Returns the time passed since `earlier`, or zero if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration.as_nanos() as u64).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration.as_nanos() as u64).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// System time
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Unix time in nanoseconds minus the monotonic clock in nanoseconds, set by `init`.
+/// Negative if the wall clock was set to a time before the uptime.
+static WALL_CLOCK_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// Wall clock time. Unlike `Instant` this can jump if the clock gets adjusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration); //Since the unix epoch
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+impl SystemTime {
+    /// Saturates at `UNIX_EPOCH`, the clock can't be set any earlier than that.
+    pub fn now() -> Self {
+        let nanos = WALL_CLOCK_OFFSET.load(Ordering::Relaxed) as i128 + now_nanos() as i128;
+        SystemTime(Duration::from_nanos(nanos.max(0).min(u64::MAX as i128) as u64))
+    }
+
+    /// Returns the time passed since `earlier`, or an error with the difference
+    /// if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, Duration> {
+        if self.0 >= earlier.0 {
+            Ok(self.0 - earlier.0)
+        } else {
+            Err(earlier.0 - self.0)
+        }
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, rhs: Duration) -> SystemTime {
+        SystemTime(self.0 + rhs)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, rhs: Duration) -> SystemTime {
+        SystemTime(self.0 - rhs)
+    }
+}
+
+/// Sets the wall clock, keeping the monotonic clock untouched.
+pub fn set_system_time(time: SystemTime) {
+    let offset = time.0.as_nanos() as i128 - now_nanos() as i128;
+    let offset = offset.max(i64::MIN as i128).min(i64::MAX as i128) as i64;
+    WALL_CLOCK_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Converts a UTC date to time since the unix epoch.
+pub fn unix_time_from_date_time(date_time: &DateTime) -> Duration {
+    // Days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = date_time.year as i64 - if date_time.month <= 2 { 1 } else { 0 };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = date_time.month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + date_time.day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400
+        + date_time.hour as i64 * 3600
+        + date_time.minute as i64 * 60
+        + date_time.second as i64;
+    Duration::from_secs(core::cmp::max(seconds, 0) as u64)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[cfg(test)]
+fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime { year, month, day, hour, minute, second }
+}
+
+#[test_case]
+fn test_unix_time_from_date_time() {
+    let seconds = |date_time: DateTime| unix_time_from_date_time(&date_time).as_secs();
+    assert_eq!(seconds(date(1970, 1, 1, 0, 0, 0)), 0);
+    assert_eq!(seconds(date(1999, 12, 31, 23, 59, 59)), 946_684_799);
+    // Right after a leap day in a year divisible by 400
+    assert_eq!(seconds(date(2000, 3, 1, 0, 0, 0)), 951_868_800);
+    assert_eq!(seconds(date(2024, 2, 29, 12, 34, 56)), 1_709_210_096);
+    // Dates before the epoch clamp to it
+    assert_eq!(seconds(date(1969, 12, 31, 23, 59, 59)), 0);
+}
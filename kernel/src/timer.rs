@@ -0,0 +1,223 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::interrupts;
+
+use crate::hardware::hpet;
+use crate::multitasking::thread::ThreadId;
+
+/// The HPET channel used as a one-shot timer for the earliest pending deadline.
+pub const HPET_CHANNEL: u8 = 1;
+
+lazy_static! {
+    static ref TIMERS: spin::Mutex<TimerQueue> = spin::Mutex::new(TimerQueue::new());
+}
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    fn new() -> Self {
+        TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// What happens when a timer fires. Callbacks run in interrupt context, so they must not block.
+pub enum TimerAction {
+    Wake(ThreadId),
+    Callback(Box<dyn FnMut() + Send>),
+}
+
+struct Timer {
+    deadline: u64,
+    action: TimerAction,
+}
+
+/// Pending timers, ordered by the HPET main counter value they fire at.
+struct TimerQueue {
+    timers: BTreeMap<TimerId, Timer>,
+    deadlines: BTreeSet<(u64, TimerId)>,
+}
+
+impl TimerQueue {
+    fn new() -> Self {
+        TimerQueue {
+            timers: BTreeMap::new(),
+            deadlines: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, id: TimerId, timer: Timer) {
+        self.deadlines.insert((timer.deadline, id));
+        self.timers.insert(id, timer);
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        let timer = self.timers.remove(&id)?;
+        self.deadlines.remove(&(timer.deadline, id));
+        Some(timer)
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.deadlines.iter().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Removes the earliest timer if it has expired.
+    fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        let &(deadline, id) = self.deadlines.iter().next()?;
+        if deadline > now {
+            return None;
+        }
+        self.remove(id)
+    }
+}
+
+/// Runs `f` on the timer queue with interrupts disabled, the timer interrupt locks it too.
+fn with_timers<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut TimerQueue) -> T,
+{
+    interrupts::without_interrupts(|| f(&mut TIMERS.lock()))
+}
+
+/// Returns the current time, in HPET main counter ticks.
+pub fn now() -> u64 {
+    hpet::read_main_counter()
+}
+
+/// Registers a timer that fires after `delay`.
+pub fn add_timer(delay: Duration, action: TimerAction) -> TimerId {
+    add_timer_at(now() + hpet::duration_to_ticks(delay), action)
+}
+
+/// Registers a timer that fires once the HPET main counter reaches `deadline`.
+pub fn add_timer_at(deadline: u64, action: TimerAction) -> TimerId {
+    let id = TimerId::new();
+    with_timers(|timers| {
+        timers.insert(id, Timer { deadline, action });
+        rearm(timers);
+    });
+    id
+}
+
+/// Cancels a timer. Returns false if it already fired or never existed.
+pub fn cancel(id: TimerId) -> bool {
+    // The action is dropped after the lock is released
+    let removed = with_timers(|timers| {
+        let removed = timers.remove(id);
+        if removed.is_some() {
+            rearm(timers);
+        }
+        removed
+    });
+    removed.is_some()
+}
+
+/// Moves a pending timer to fire after `delay` instead.
+/// Returns an error if the timer already fired or never existed.
+pub fn reschedule(id: TimerId, delay: Duration) -> Result<(), ()> {
+    let deadline = now() + hpet::duration_to_ticks(delay);
+    with_timers(|timers| {
+        let mut timer = timers.remove(id).ok_or(())?;
+        timer.deadline = deadline;
+        timers.insert(id, timer);
+        rearm(timers);
+        Ok(())
+    })
+}
+
+/// Returns true if the timer hasn't fired or been cancelled yet.
+pub fn is_pending(id: TimerId) -> bool {
+    with_timers(|timers| timers.timers.contains_key(&id))
+}
+
+/// Points the HPET comparator at the earliest deadline.
+/// Returns false if that deadline has already passed.
+fn rearm(timers: &TimerQueue) -> bool {
+    match timers.next_deadline() {
+        Some(deadline) => hpet::hpet_set_oneshot_timer(HPET_CHANNEL, deadline),
+        None => {
+            hpet::hpet_cancel_oneshot_timer(HPET_CHANNEL);
+            true
+        },
+    }
+}
+
+/// Fires every expired timer and arms the comparator for the next one.
+/// Called from the HPET one-shot interrupt handler and the scheduler tick.
+pub fn handle_interrupt() {
+    loop {
+        // Timers are taken out one at a time, so callbacks can add or cancel timers themselves
+        let expired = with_timers(|timers| timers.pop_expired(now()));
+        match expired {
+            Some(timer) => fire(timer),
+            None => {
+                if with_timers(|timers| rearm(timers)) {
+                    break;
+                }
+            },
+        }
+    }
+}
+
+fn fire(timer: Timer) {
+    match timer.action {
+        TimerAction::Wake(thread_id) => {
+            // The thread might have exited in the meantime
+            let _ = crate::multitasking::wake(thread_id);
+        },
+        TimerAction::Callback(mut callback) => callback(),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A timer queue entry that doesn't touch the scheduler if it ever fired.
+#[cfg(test)]
+fn test_timer(deadline: u64) -> Timer {
+    Timer { deadline, action: TimerAction::Callback(Box::new(|| {})) }
+}
+
+#[test_case]
+fn test_timer_queue_order() {
+    let mut timers = TimerQueue::new();
+    let (late, early, middle) = (TimerId::new(), TimerId::new(), TimerId::new());
+    timers.insert(late, test_timer(300));
+    timers.insert(early, test_timer(100));
+    timers.insert(middle, test_timer(200));
+    assert_eq!(timers.next_deadline(), Some(100));
+
+    // Nothing has expired yet
+    assert!(timers.pop_expired(99).is_none());
+
+    let fired: alloc::vec::Vec<u64> = core::iter::from_fn(|| timers.pop_expired(250))
+        .map(|timer| timer.deadline)
+        .collect();
+    assert_eq!(fired, vec![100, 200]);
+    assert_eq!(timers.next_deadline(), Some(300));
+}
+
+#[test_case]
+fn test_timer_queue_cancel() {
+    let mut timers = TimerQueue::new();
+    let (first, second) = (TimerId::new(), TimerId::new());
+    timers.insert(first, test_timer(100));
+    timers.insert(second, test_timer(100));
+
+    assert_eq!(timers.remove(first).map(|timer| timer.deadline), Some(100));
+    assert!(timers.remove(first).is_none());
+    // A timer with the same deadline isn't affected
+    assert_eq!(timers.next_deadline(), Some(100));
+    assert!(timers.pop_expired(100).is_some());
+    assert!(timers.pop_expired(u64::MAX).is_none());
+    assert_eq!(timers.next_deadline(), None);
+}
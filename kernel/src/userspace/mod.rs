@@ -1,58 +1,70 @@
 use x86_64::VirtAddr;
 
-use crate::multitasking::{self, thread::Thread, with_scheduler};
+use crate::memory::AddressSpace;
+use crate::multitasking::{self, thread::Thread, process::Process, with_scheduler};
 
 global_asm!(include_str!("userspace.s"));
 
-#[naked]
-unsafe extern "C" fn syscall_entry_fn() {
-    //TODO: Jump to kernel stack
-    //      Load old IP from RCX
-    //      Load syscall info from RDI
-
-    //Handle syscall
-
-    //TODO: Jump to task stack
-    //      Load old IP into RCX
-    //      Move back to RING 3
-}
+pub mod syscall;
 
+/// Loads the userspace program and hands its first thread to the scheduler.
+/// The syscall entry is set up by `crate::init` already.
 pub fn init() {
-    crate::gdt::setup_usermode_gdt();
-    x86_64::registers::model_specific::LStar::write(VirtAddr::new(syscall_entry_fn as u64));
-    trace!("Usermode gdt setup!");
-
-    let userspace_addr = 0xFF00_0000;
-    // let userspace_addr = 0x1000_0000;
+    let userspace_addr = crate::memory::USER_CODE_START;
 
     // let test_elf = include_bytes!("../../test.elf");
     let test_elf = include_bytes!("../../../target/x86_64-os_project/release/userspace");
     let binary = elfloader::ElfBinary::new("test", test_elf).expect("Failed to load ELF file!");
-    let mut loader = crate::custom_elfloader::CustomElfLoader::new(userspace_addr);
+
+    let mut address_space = {
+        let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+        AddressSpace::new(frame_allocator.as_mut().unwrap()).expect("Failed to create address space!")
+    };
+    let mut loader = crate::custom_elfloader::CustomElfLoader::new(userspace_addr, &mut address_space);
     binary.load(&mut loader).expect("Can't load the binary!");
 
     let entry_point = userspace_addr + binary.entry_point();
     info!("Entry point: {:#X}", entry_point);
 
-    // panic!("INIT_USERSPACE ADDR: 0x{:0X}", entry_point);
-    // loop {}
-
     let mut mapper = crate::memory::MAPPER.lock();
     let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
-    let stack_bounds = crate::memory::alloc_user_stack(2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).expect("Failed to map user stack!");
+    let stack_bounds = address_space.alloc_user_stack(2, frame_allocator.as_mut().unwrap()).expect("Failed to map user stack!");
+    let process = Process::new(address_space);
+
+    //The thread starts in ring 0 on its own kernel stack, which syscalls will use later on
+    let mut user_thread = Thread::create_from_closure(
+        move || unsafe { enter_usermode(entry_point, stack_bounds.end()) },
+        2,
+        mapper.as_mut().unwrap(),
+        frame_allocator.as_mut().unwrap(),
+    ).expect("Failed to create user thread!");
+    user_thread.set_process(process.id());
+    with_scheduler(|s| {
+        s.add_process(process);
+        s.add_new_thread(user_thread);
+    });
+}
 
-    info!("hello?");
+/// Sets the stack used when entering the kernel from ring 3, both for syscalls
+/// and for interrupts (through the TSS).
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    syscall::set_kernel_stack(stack_end);
+    unsafe { crate::gdt::set_privilege_stack(stack_end); }
+}
 
-    unsafe {
-        asm!("cli"); //Disable interrupts
-        asm!("mov rcx, {0}", in(reg) entry_point);
-        // x86_64::registers::model_specific::LStar::write(VirtAddr::new(entry_point));
-        // unsafe { asm!("mov rsp, {0}", in(reg) stack_bounds.end().as_u64()); } //Jump to userspace stack
-        stack_bounds.switch_to();
-        asm!("pushfq");
-        asm!("pop r11");
-        asm!("sysretq");
-    }
+/// Drops into ring 3 at `entry_point`, using `stack_end` as the user stack.
+/// The GS base is swapped to the user one on the way out, like `asm_syscall_entry` does.
+pub unsafe fn enter_usermode(entry_point: u64, stack_end: VirtAddr) -> ! {
+    asm!("
+        cli
+        mov rsp, {0}
+        swapgs
+        sysretq",
+        in(reg) stack_end.as_u64(),
+        in("rcx") entry_point,
+        in("r11") 0x202u64, //RFLAGS with interrupts enabled
+        options(noreturn)
+    );
 }
 
 //Pagefault occurs because this function is memory mapped to non-accessible page.
@@ -0,0 +1,190 @@
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{LStar, Msr};
+
+use crate::memory::{USER_SPACE_START, USER_SPACE_END};
+use crate::multitasking::{self, with_scheduler};
+
+global_asm!(include_str!("syscall.s"));
+
+const IA32_FMASK: u32 = 0xC000_0084;
+
+/// Biggest buffer a single syscall is allowed to pass to the kernel.
+const MAX_USER_BUFFER: u64 = 4096;
+
+extern "C" {
+    fn asm_syscall_entry();
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Syscall frame
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The user register state pushed by `asm_syscall_entry`.
+/// The field order has to match the push order in `syscall.s`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Returns the syscall arguments in ABI order.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Dispatch table
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    InvalidSyscall = 1,
+    InvalidArgument = 2,
+    InvalidAddress = 3,
+}
+
+impl SyscallError {
+    /// Errors are returned to userspace as negative numbers in RAX.
+    pub fn as_u64(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+pub type SyscallHandler = fn(&mut SyscallFrame) -> Result<u64, SyscallError>;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_LOG: u64 = 2;
+pub const SYS_THREAD_ID: u64 = 3;
+pub const SYS_TIME: u64 = 4;
+
+/// Indexed by the syscall number passed in RAX.
+static SYSCALL_TABLE: [SyscallHandler; 5] = [
+    sys_exit,
+    sys_yield,
+    sys_log,
+    sys_thread_id,
+    sys_time,
+];
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // `sysretq` with a non-canonical RIP faults in ring 0, so only ever return into user space
+    if frame.rip < USER_SPACE_START || frame.rip >= USER_SPACE_END {
+        warn!("Thread returned from syscall to invalid address {:#X}!", frame.rip);
+        multitasking::exit_thread();
+    }
+    // Another thread of the process faulted
+    if multitasking::current_process_killed() {
+        multitasking::exit_thread();
+    }
+
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::InvalidSyscall),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => err.as_u64(),
+    };
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Syscall handlers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// exit(code) -> !
+fn sys_exit(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let thread_id = with_scheduler(|s| s.current_thread_id());
+    debug!("Thread {} exited with code {}", thread_id.as_u64(), frame.rdi as i64);
+    multitasking::exit_thread_with(multitasking::process::ExitReason::Exited(frame.rdi as i64));
+}
+
+/// yield()
+fn sys_yield(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    multitasking::yield_now();
+    Ok(0)
+}
+
+/// log(ptr, len)
+fn sys_log(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let bytes = user_slice(frame.rdi, frame.rsi)?;
+    let msg = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    info!("[USER] {}", msg);
+    Ok(bytes.len() as u64)
+}
+
+/// thread_id() -> id
+fn sys_thread_id(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(with_scheduler(|s| s.current_thread_id()).as_u64())
+}
+
+/// time() -> seconds since the unix epoch
+fn sys_time(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    use crate::time::{SystemTime, UNIX_EPOCH};
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(since_epoch.as_secs())
+}
+
+/// Checks that a buffer passed by userspace lies in the user half and that every page of it
+/// is mapped user accessible, so kernel memory can't be passed off as a user buffer.
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    use x86_64::structures::paging::{Page, PageTableFlags as Flags, Size4KiB};
+    use core::sync::atomic::Ordering;
+
+    if len > MAX_USER_BUFFER {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let end = ptr.checked_add(len).ok_or(SyscallError::InvalidAddress)?;
+    if ptr < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(SyscallError::InvalidAddress);
+    }
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    // The calling thread's address space is active, so plain translation works
+    let phys_mem_offset = VirtAddr::new(crate::memory::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let first_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(ptr));
+    let last_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let flags = unsafe { crate::memory::effective_page_flags(page.start_address(), phys_mem_offset) };
+        match flags {
+            Some(flags) if flags.contains(Flags::PRESENT | Flags::USER_ACCESSIBLE) => {}
+            _ => return Err(SyscallError::InvalidAddress),
+        }
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Setup
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Points `LStar` at the syscall entry and masks interrupts, the direction flag
+/// and the trap flag on entry.
+pub fn init() {
+    use x86_64::registers::rflags::RFlags;
+
+    LStar::write(VirtAddr::new(asm_syscall_entry as u64));
+    let mask = RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG;
+    unsafe {
+        Msr::new(IA32_FMASK).write(mask.bits());
+    }
+}
+
+/// Sets the stack `asm_syscall_entry` switches to on the current CPU.
+/// Called by the scheduler every time a thread is switched to.
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    crate::smp::percpu::current().set_kernel_stack(stack_end);
+}
@@ -0,0 +1,52 @@
+//; in src/userspace/syscall.s
+//; use intel asm syntax
+.intel_syntax noprefix
+
+//; Entry point of the `syscall` instruction, `LStar` points here.
+//; On entry:
+//;     rax                         = syscall number
+//;     rdi, rsi, rdx, r10, r8, r9  = arguments
+//;     rcx                         = user instruction pointer
+//;     r11                         = user RFLAGS
+//; Interrupts are masked through SFMASK, so nothing can run between the `swapgs` and the stack switch.
+//; Offsets into `PerCpu`, see `smp/percpu.rs`
+.set PERCPU_USER_RSP, 8
+.set PERCPU_KERNEL_RSP, 16
+
+.global asm_syscall_entry
+asm_syscall_entry:
+    swapgs                                  //; GS base points at the per-CPU data now
+    mov gs:[PERCPU_USER_RSP], rsp           //; save the user stack pointer
+    mov rsp, gs:[PERCPU_KERNEL_RSP]         //; switch to the kernel stack of the thread
+
+    //; Build a `SyscallFrame` on the kernel stack
+    push qword ptr gs:[PERCPU_USER_RSP]
+    push r11
+    push rcx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp                //; pass a pointer to the frame as argument
+    call syscall_dispatch       //; the result is written back into the frame
+
+    //; The handler may have switched threads and enabled interrupts,
+    //; an interrupt on the user stack or with the user GS base would be fatal
+    cli
+
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rcx
+    pop r11
+    pop rsp                     //; back on the user stack
+    swapgs                      //; restore the user GS base
+    sysretq
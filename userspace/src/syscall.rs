@@ -0,0 +1,57 @@
+//Syscall numbers, these have to match `kernel/src/userspace/syscall.rs`
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_LOG: u64 = 2;
+pub const SYS_THREAD_ID: u64 = 3;
+pub const SYS_TIME: u64 = 4;
+
+unsafe fn syscall0(number: u64) -> u64 {
+    let ret: u64;
+    asm!("syscall",
+        inlateout("rax") number => ret,
+        out("rcx") _, out("r11") _,
+        options(nostack));
+    ret
+}
+
+unsafe fn syscall1(number: u64, arg0: u64) -> u64 {
+    let ret: u64;
+    asm!("syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0,
+        out("rcx") _, out("r11") _,
+        options(nostack));
+    ret
+}
+
+unsafe fn syscall2(number: u64, arg0: u64, arg1: u64) -> u64 {
+    let ret: u64;
+    asm!("syscall",
+        inlateout("rax") number => ret,
+        in("rdi") arg0, in("rsi") arg1,
+        out("rcx") _, out("r11") _,
+        options(nostack));
+    ret
+}
+
+pub fn exit(code: i64) -> ! {
+    unsafe { syscall1(SYS_EXIT, code as u64); }
+    loop {} //The kernel never returns from this syscall
+}
+
+pub fn yield_now() {
+    unsafe { syscall0(SYS_YIELD); }
+}
+
+pub fn log(msg: &str) {
+    unsafe { syscall2(SYS_LOG, msg.as_ptr() as u64, msg.len() as u64); }
+}
+
+pub fn thread_id() -> u64 {
+    unsafe { syscall0(SYS_THREAD_ID) }
+}
+
+/// Seconds since the unix epoch
+pub fn time() -> u64 {
+    unsafe { syscall0(SYS_TIME) }
+}